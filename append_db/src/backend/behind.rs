@@ -0,0 +1,323 @@
+pub use crate::backend::class::{Envelope, HistoryPoint, State, StateBackend};
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{mpsc, watch};
+
+#[derive(Error, Debug)]
+pub enum Error<E: std::error::Error + 'static> {
+    #[error(transparent)]
+    Backend(E),
+    #[error("Write-behind writer is halted: {0}")]
+    Halted(String),
+    #[error("Updates {0:?} failed to be stored")]
+    Dropped(Vec<u64>),
+}
+
+/// Receives updates that failed to be stored and the error
+pub type FailureCallback<B> =
+    Box<dyn Fn(Vec<Envelope<<B as StateBackend>::State>>, <B as StateBackend>::Err) + Send + Sync>;
+
+/// What the writer does when the inner backend fails to store a batch of updates
+pub enum FailurePolicy<B: StateBackend> {
+    /// Stop writing. Queued updates are not stored, further writes, `flush` and
+    /// `wait_durable` fail with `Error::Halted`.
+    Halt,
+    /// Write the batch again after a delay that doubles from `initial` up to `max`.
+    /// Later updates wait in the queue meanwhile.
+    Retry { initial: Duration, max: Duration },
+    /// Pass the batch and the error to the callback and go on with the next batch.
+    /// The batch is not stored, the first `flush` or `wait_durable` that covers it
+    /// fails with `Error::Dropped`.
+    Callback(FailureCallback<B>),
+}
+
+/// Progress of the writer
+#[derive(Debug, Clone, Default)]
+struct Written {
+    /// Every submitted update with sequence number up to this one is done: either
+    /// stored or dropped
+    seq: u64,
+    /// Amount of done updates
+    done: u64,
+    /// Sequence numbers of dropped updates that are not reported yet
+    dropped: BTreeSet<u64>,
+    /// Error that halted the writer
+    halted: Option<String>,
+}
+
+struct Progress {
+    /// Amount of submitted updates
+    submitted: AtomicU64,
+    /// Sequence numbers of submitted updates that are not done yet with their amounts,
+    /// as a snapshot shares the number with an incremental update
+    pending: Mutex<BTreeMap<u64, usize>>,
+    written: watch::Sender<Written>,
+}
+
+impl Progress {
+    fn submit<St: State>(&self, envelopes: &[Envelope<St>]) {
+        let mut pending = self.pending.lock().expect("not poisoned");
+        for envelope in envelopes {
+            *pending.entry(envelope.seq).or_default() += 1;
+        }
+        self.submitted
+            .fetch_add(envelopes.len() as u64, Ordering::SeqCst);
+    }
+
+    fn done<St: State>(&self, envelopes: &[Envelope<St>], stored: bool) {
+        let mut pending = self.pending.lock().expect("not poisoned");
+        for envelope in envelopes {
+            if let Some(amount) = pending.get_mut(&envelope.seq) {
+                *amount -= 1;
+                if *amount == 0 {
+                    pending.remove(&envelope.seq);
+                }
+            }
+        }
+        let last = envelopes.iter().map(|e| e.seq).max().unwrap_or(0);
+        let seq = match pending.keys().next() {
+            Some(first) => first.saturating_sub(1),
+            None => last,
+        };
+        self.written.send_modify(|written| {
+            written.seq = written.seq.max(seq);
+            written.done += envelopes.len() as u64;
+            if !stored {
+                written.dropped.extend(envelopes.iter().map(|e| e.seq));
+            }
+        });
+    }
+
+    fn halt(&self, reason: String) {
+        self.written
+            .send_modify(|written| written.halted = Some(reason));
+    }
+
+    fn halted(&self) -> Option<String> {
+        self.written.borrow().halted.clone()
+    }
+
+    /// Wait until the progress satisfies the condition
+    async fn wait<E, F>(&self, reached: F) -> Result<(), Error<E>>
+    where
+        E: std::error::Error + 'static,
+        F: Fn(&Written) -> bool,
+    {
+        let mut written = self.written.subscribe();
+        loop {
+            {
+                let current = written.borrow();
+                if let Some(reason) = &current.halted {
+                    return Err(Error::Halted(reason.clone()));
+                }
+                if reached(&current) {
+                    return Ok(());
+                }
+            }
+            // The sender lives in `self`, so the channel is never closed here
+            let _ = written.changed().await;
+        }
+    }
+}
+
+/// Backend wrapper that persists updates in the background. `write` and `write_batch`
+/// return as soon as the updates are put into a bounded queue of `capacity` writes,
+/// so `AppendDb::update` doesn't wait for the storage. Use `flush` before shutdown
+/// and `wait_durable` when a particular update must be stored.
+///
+/// The writer task stores queued writes with `StateBackend::write_batch` of the inner
+/// backend, taking everything that is queued at once. Failures are handled according
+/// to the `FailurePolicy`. Note that subscribers of `AppendDb::subscribe` receive
+/// updates once they are queued.
+///
+/// Reads wait until the queued writes are done, so `AppendDb::reload`, `state_at` and
+/// compaction see the whole history. They fail with `Error::Halted` once the writer is
+/// halted, `backend` is still available to read what is stored.
+///
+/// Use the wrapper with `Durability::MemoryFirst`. `Durability::WriteAhead` doesn't make
+/// updates durable before they become visible, as writes return once queued.
+pub struct WriteBehind<B: StateBackend> {
+    pub backend: Arc<B>,
+    queue: mpsc::Sender<Vec<Envelope<B::State>>>,
+    progress: Arc<Progress>,
+}
+
+impl<B: StateBackend> Clone for WriteBehind<B> {
+    fn clone(&self) -> Self {
+        WriteBehind {
+            backend: self.backend.clone(),
+            queue: self.queue.clone(),
+            progress: self.progress.clone(),
+        }
+    }
+}
+
+impl<B: StateBackend + 'static> WriteBehind<B> {
+    /// Start the writer task. It stores the remaining queue and stops when all clones
+    /// of the wrapper are dropped.
+    pub fn new(backend: B, capacity: NonZeroUsize, policy: FailurePolicy<B>) -> Self {
+        let backend = Arc::new(backend);
+        let (queue, writes) = mpsc::channel(capacity.get());
+        let progress = Arc::new(Progress {
+            submitted: AtomicU64::new(0),
+            pending: Mutex::new(BTreeMap::new()),
+            written: watch::channel(Written::default()).0,
+        });
+        tokio::spawn(run(backend.clone(), writes, progress.clone(), policy));
+        WriteBehind {
+            backend,
+            queue,
+            progress,
+        }
+    }
+
+    /// Wait until all updates that are submitted so far are stored. Fails with
+    /// `Error::Dropped` if some updates were dropped since the last reported drop.
+    pub async fn flush(&self) -> Result<(), Error<B::Err>> {
+        self.settle().await?;
+        self.check_dropped(u64::MAX)
+    }
+
+    /// Wait until the submitted update with the sequence number and all submitted
+    /// updates before it are stored, e.g. `wait_durable(db.seq())` after `AppendDb::update`.
+    /// Fails with `Error::Dropped` if some of them were dropped and not reported yet.
+    pub async fn wait_durable(&self, seq: u64) -> Result<(), Error<B::Err>> {
+        self.progress.wait(|w| w.seq >= seq).await?;
+        self.check_dropped(seq)
+    }
+
+    /// Sequence number up to which submitted updates are done: either stored or dropped
+    pub fn durable_seq(&self) -> u64 {
+        self.progress.written.borrow().seq
+    }
+
+    /// Wait until all updates that are submitted so far are done
+    async fn settle(&self) -> Result<(), Error<B::Err>> {
+        let submitted = self.progress.submitted.load(Ordering::SeqCst);
+        self.progress.wait(|w| w.done >= submitted).await
+    }
+
+    /// Report dropped updates up to the sequence number once
+    fn check_dropped(&self, seq: u64) -> Result<(), Error<B::Err>> {
+        let mut dropped = vec![];
+        self.progress.written.send_if_modified(|written| {
+            dropped.extend(written.dropped.range(..=seq).copied());
+            for seq in &dropped {
+                written.dropped.remove(seq);
+            }
+            !dropped.is_empty()
+        });
+        if dropped.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Dropped(dropped))
+        }
+    }
+
+    async fn submit(&self, envelopes: Vec<Envelope<B::State>>) -> Result<(), Error<B::Err>> {
+        if let Some(reason) = self.progress.halted() {
+            return Err(Error::Halted(reason));
+        }
+        self.progress.submit(&envelopes);
+        self.queue
+            .send(envelopes)
+            .await
+            .map_err(|_| Error::Halted(self.progress.halted().unwrap_or_default()))
+    }
+}
+
+/// Body of the writer task
+async fn run<B: StateBackend>(
+    backend: Arc<B>,
+    mut writes: mpsc::Receiver<Vec<Envelope<B::State>>>,
+    progress: Arc<Progress>,
+    policy: FailurePolicy<B>,
+) {
+    while let Some(mut batch) = writes.recv().await {
+        while let Ok(more) = writes.try_recv() {
+            batch.extend(more);
+        }
+        let mut delay = None;
+        let stored = loop {
+            let err = match backend.write_batch(batch.clone()).await {
+                Ok(()) => break true,
+                Err(e) => e,
+            };
+            match &policy {
+                FailurePolicy::Halt => {
+                    log::error!("Write-behind writer of {} halted: {}", B::State::TABLE, err);
+                    progress.halt(err.to_string());
+                    return;
+                }
+                FailurePolicy::Retry { initial, max } => {
+                    let current = delay.map_or(*initial, |d: Duration| (d * 2).min(*max));
+                    log::warn!(
+                        "Write-behind writer of {} retries in {:?}: {}",
+                        B::State::TABLE,
+                        current,
+                        err
+                    );
+                    tokio::time::sleep(current).await;
+                    delay = Some(current);
+                }
+                FailurePolicy::Callback(callback) => {
+                    callback(batch.clone(), err);
+                    break false;
+                }
+            }
+        };
+        progress.done(&batch, stored);
+    }
+}
+
+#[async_trait]
+impl<B: StateBackend + 'static> StateBackend for WriteBehind<B> {
+    type State = B::State;
+    type Err = Error<B::Err>;
+
+    async fn write(&self, upd: Envelope<Self::State>) -> Result<(), Self::Err> {
+        self.submit(vec![upd]).await
+    }
+
+    async fn write_batch(&self, upds: Vec<Envelope<Self::State>>) -> Result<(), Self::Err> {
+        self.submit(upds).await
+    }
+
+    async fn updates(&self) -> Result<Vec<Envelope<Self::State>>, Self::Err> {
+        self.settle().await?;
+        self.backend.updates().await.map_err(Error::Backend)
+    }
+
+    fn updates_stream(&self) -> BoxStream<'_, Result<Envelope<Self::State>, Self::Err>> {
+        stream::once(self.settle())
+            .map_ok(|()| self.backend.updates_stream().map_err(Error::Backend))
+            .try_flatten()
+            .boxed()
+    }
+
+    async fn compact(&self, keep_snapshots: NonZeroUsize) -> Result<u64, Self::Err> {
+        self.settle().await?;
+        self.backend
+            .compact(keep_snapshots)
+            .await
+            .map_err(Error::Backend)
+    }
+
+    async fn updates_until(
+        &self,
+        point: HistoryPoint,
+    ) -> Result<Option<Vec<Envelope<Self::State>>>, Self::Err> {
+        self.settle().await?;
+        self.backend
+            .updates_until(point)
+            .await
+            .map_err(Error::Backend)
+    }
+}
@@ -1,22 +1,98 @@
+use crate::backend::memory::history_until;
 use async_trait::async_trait;
+use chrono::prelude::*;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt::Debug;
+use std::num::NonZeroUsize;
 
 /// Describes a storing backend that can
 /// save and load given internal type of updates for
 /// state.
 #[async_trait]
-pub trait StateBackend {
+pub trait StateBackend: Send + Sync {
     /// Aggregated state in memory
-    type State: Clone + State + 'static;
+    type State: Clone + State + Send + 'static;
     /// Errors that can occur in the backend
-    type Err: Debug + Error + 'static;
+    type Err: Debug + Error + Send + 'static;
+
+    /// Write down state update into storage. Updates can arrive out of order, so the
+    /// storage should keep their sequence numbers to restore the order on reading.
+    async fn write(&self, upd: Envelope<Self::State>) -> Result<(), Self::Err>;
+
+    /// Write down several updates atomically: either all of them are stored or none.
+    ///
+    /// The default implementation writes updates one by one and is not atomic, so
+    /// backends are expected to override it.
+    async fn write_batch(&self, upds: Vec<Envelope<Self::State>>) -> Result<(), Self::Err> {
+        for upd in upds {
+            self.write(upd).await?;
+        }
+        Ok(())
+    }
+
+    /// Collect all updates since the latest snapshot, ordered by sequence number
+    /// (see `Envelope::order_key`). The snapshot itself is the first element.
+    async fn updates(&self) -> Result<Vec<Envelope<Self::State>>, Self::Err>;
+
+    /// Same as `updates`, but yields updates one by one, oldest first. Backends
+    /// should override it to avoid collecting all updates in memory, the default
+    /// implementation simply wraps `updates`.
+    fn updates_stream(&self) -> BoxStream<'_, Result<Envelope<Self::State>, Self::Err>> {
+        stream::once(self.updates())
+            .map_ok(|updates| stream::iter(updates.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Remove history that is not needed to load the state: everything before the
+    /// `keep_snapshots`-th most recent snapshot. Returns amount of removed updates.
+    ///
+    /// Afterwards `updates_until` returns `None` for points of history before the oldest
+    /// kept snapshot. The default implementation keeps everything.
+    async fn compact(&self, _keep_snapshots: NonZeroUsize) -> Result<u64, Self::Err> {
+        Ok(0)
+    }
+
+    /// Collect updates that rebuild the state as it was at the given point of history:
+    /// the latest snapshot at or before the point followed by incremental updates up to
+    /// the point, ordered the same way as `updates`. If there is no such snapshot, the
+    /// incremental updates start from the very first one. Returns `None` if the point
+    /// is older than the retained history, see `HistoryPoint::is_retained`.
+    ///
+    /// The default implementation picks updates from `updates`, so only points since the
+    /// latest snapshot are available. Backends are expected to override it.
+    async fn updates_until(
+        &self,
+        point: HistoryPoint,
+    ) -> Result<Option<Vec<Envelope<Self::State>>>, Self::Err> {
+        Ok(history_until(&self.updates().await?, point))
+    }
+}
 
-    /// Write down state update into storage
-    async fn write(&self, upd: SnapshotedUpdate<Self::State>) -> Result<(), Self::Err>;
+/// Point of history of a state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryPoint {
+    /// Just after the update with given sequence number
+    Seq(u64),
+    /// The last update created at or before given time
+    Time(DateTime<Utc>),
+}
 
-    /// Collect all updates until first snapshot in the chain
-    async fn updates(&self) -> Result<Vec<SnapshotedUpdate<Self::State>>, Self::Err>;
+impl HistoryPoint {
+    /// Whether the state at the point can be rebuilt from history that starts with the
+    /// given update. After `StateBackend::compact` history starts with a snapshot and
+    /// points before it are lost.
+    pub fn is_retained<St: State>(&self, oldest: &Envelope<St>) -> bool {
+        if !oldest.update.is_snapshot() || oldest.seq == 0 {
+            return true;
+        }
+        match *self {
+            HistoryPoint::Seq(seq) => seq >= oldest.seq,
+            HistoryPoint::Time(t) => t >= oldest.created,
+        }
+    }
 }
 
 /// Aggregated state that could be updated by small updates
@@ -31,6 +107,12 @@ pub trait State {
 
     /// Update the state with incremental part
     fn update(&mut self, upd: Self::Update) -> Result<(), Self::Err>;
+
+    /// Relative cost of replaying the update on load. Used by `SnapshotPolicy`
+    /// to decide when it is time to make a snapshot.
+    fn replay_cost(_upd: &Self::Update) -> u64 {
+        1
+    }
 }
 
 /// Update with added shapshot to capture points when
@@ -47,3 +129,119 @@ impl<St: State> SnapshotedUpdate<St> {
         matches!(self, SnapshotedUpdate::Snapshot(_))
     }
 }
+
+/// Attribution of an update: who made it and in which context
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// User or service that made the update
+    pub actor: Option<String>,
+    /// Identifier of the whole operation the update belongs to
+    pub correlation_id: Option<String>,
+    /// Identifier of the command or event that caused the update
+    pub causation_id: Option<String>,
+    /// Free-form application specific headers
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    pub fn with_correlation_id(mut self, id: impl Into<String>) -> Self {
+        self.correlation_id = Some(id.into());
+        self
+    }
+
+    pub fn with_causation_id(mut self, id: impl Into<String>) -> Self {
+        self.causation_id = Some(id.into());
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+}
+
+/// Update together with the global sequence number assigned by `AppendDb` when
+/// the update was applied to the state.
+pub struct Envelope<St: State> {
+    /// For incremental updates this is the number of the update itself. Snapshots
+    /// carry the number of the last incremental update they include.
+    pub seq: u64,
+    /// Time when the update was made
+    pub created: DateTime<Utc>,
+    /// Who and why made the update
+    pub meta: Metadata,
+    pub update: SnapshotedUpdate<St>,
+}
+
+impl<St: State> Envelope<St> {
+    /// Wrap update made right now without metadata
+    pub fn new(seq: u64, update: SnapshotedUpdate<St>) -> Self {
+        Self::with_created(seq, Utc::now(), update)
+    }
+
+    pub fn with_created(seq: u64, created: DateTime<Utc>, update: SnapshotedUpdate<St>) -> Self {
+        Envelope {
+            seq,
+            created,
+            meta: Metadata::default(),
+            update,
+        }
+    }
+
+    /// Attach metadata to the update
+    pub fn with_meta(mut self, meta: Metadata) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    /// Key that defines the replay order: by sequence number and snapshots
+    /// go after the incremental update with the same number.
+    pub fn order_key(&self) -> (u64, bool) {
+        (self.seq, self.update.is_snapshot())
+    }
+}
+
+impl<St: State> Debug for Envelope<St>
+where
+    SnapshotedUpdate<St>: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Envelope")
+            .field("seq", &self.seq)
+            .field("created", &self.created)
+            .field("meta", &self.meta)
+            .field("update", &self.update)
+            .finish()
+    }
+}
+
+impl<St: State> Clone for Envelope<St>
+where
+    SnapshotedUpdate<St>: Clone,
+{
+    fn clone(&self) -> Self {
+        Envelope {
+            seq: self.seq,
+            created: self.created,
+            meta: self.meta.clone(),
+            update: self.update.clone(),
+        }
+    }
+}
+
+impl<St: State> PartialEq for Envelope<St>
+where
+    SnapshotedUpdate<St>: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.seq == other.seq
+            && self.created == other.created
+            && self.meta == other.meta
+            && self.update == other.update
+    }
+}
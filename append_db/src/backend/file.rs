@@ -0,0 +1,316 @@
+pub use crate::backend::class::{
+    Envelope, HistoryPoint, Metadata, SnapshotedUpdate, State, StateBackend,
+};
+use crate::backend::memory::history_until;
+use crate::backend::record::{Body, Record};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::SeekFrom;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("File error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to decode/encode JSON: {0}")]
+    Encoding(#[from] serde_json::Error),
+    #[error("Corrupted record at offset {0}")]
+    Corrupted(u64),
+}
+
+/// When the log file is flushed to the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    /// After every write, the slowest and the safest option
+    Always,
+    /// After given amount of writes
+    Every(usize),
+    /// Leave it to the operating system
+    Never,
+}
+
+/// Storage that appends every update as a JSON line to a local file. Next to the log
+/// it keeps `<path>.idx` file with the offset of the latest snapshot, so loading doesn't
+/// read the whole log.
+#[derive(Clone)]
+pub struct FileLog<St: State> {
+    pub path: PathBuf,
+    pub fsync: Fsync,
+    inner: Arc<Mutex<Inner>>,
+    pub state_proxy: PhantomData<St>,
+}
+
+struct Inner {
+    file: File,
+    /// Length of the valid part of the log
+    len: u64,
+    /// Loading starts from this offset. All records before it are covered by the
+    /// latest snapshot.
+    start: u64,
+    /// Sequence numbers and offsets of incremental updates after `start`
+    tail: Vec<(u64, u64)>,
+    /// Writes since the last fsync
+    unsynced: usize,
+}
+
+impl<St: State> FileLog<St>
+where
+    St: Serialize + DeserializeOwned,
+    St::Update: Serialize + DeserializeOwned,
+{
+    /// Open or create the log. Incomplete records at the end of the log that were
+    /// left by a crash are cut off.
+    pub async fn open<P: AsRef<Path>>(path: P, fsync: Fsync) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .await?;
+        let file_len = file.metadata().await?.len();
+        let mut start = match fs::read_to_string(index_path(&path)).await {
+            Ok(s) => s.trim().parse().unwrap_or(0).min(file_len),
+            Err(_) => 0,
+        };
+        // Index always points to the beginning of a line
+        if start > 0 {
+            file.seek(SeekFrom::Start(start - 1)).await?;
+            if file.read_u8().await? != b'\n' {
+                start = 0;
+            }
+        }
+        let (start, scanned) = match scan::<St>(&mut file, start).await {
+            Ok(scanned) => (start, scanned),
+            // Stale index, fall back to the whole log
+            Err(_) if start > 0 => (0, scan::<St>(&mut file, 0).await?),
+            Err(e) => return Err(e),
+        };
+        if scanned.len < file_len {
+            log::warn!(
+                "Cutting off incomplete records at the end of {}",
+                path.display()
+            );
+            file.set_len(scanned.len).await?;
+            file.sync_all().await?;
+        }
+
+        Ok(FileLog {
+            path,
+            fsync,
+            inner: Arc::new(Mutex::new(Inner {
+                file,
+                len: scanned.len,
+                start,
+                tail: scanned.tail,
+                unsynced: 0,
+            })),
+            state_proxy: PhantomData,
+        })
+    }
+
+    /// Append records to the log as a single write. Records of a batch carry amount of
+    /// records that follow them, so a torn batch is dropped as a whole on opening.
+    async fn append(&self, envelopes: Vec<Envelope<St>>) -> Result<(), Error> {
+        let mut inner = self.inner.lock().await;
+        let mut buf = vec![];
+        let mut offsets = vec![];
+        let mut snapshot = None;
+        let count = envelopes.len();
+        for (i, envelope) in envelopes.into_iter().enumerate() {
+            let offset = inner.len + buf.len() as u64;
+            match &envelope.update {
+                SnapshotedUpdate::Snapshot(_) => snapshot = Some((envelope.seq, offset)),
+                SnapshotedUpdate::Incremental(_) => offsets.push((envelope.seq, offset)),
+            }
+            let record = Record::from_envelope(envelope, (count - i - 1) as u64);
+            serde_json::to_writer(&mut buf, &record)?;
+            buf.push(b'\n');
+        }
+        // Write errors of `tokio::fs::File` may surface only on flush
+        let written = match inner.file.write_all(&buf).await {
+            Ok(()) => inner.file.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            // Cut off the partially written records, so the next append starts at the
+            // end of the valid part of the log
+            let len = inner.len;
+            if let Err(e) = inner.file.set_len(len).await {
+                log::error!("Failed to truncate {}: {}", self.path.display(), e);
+            }
+            return Err(e.into());
+        }
+        inner.len += buf.len() as u64;
+        inner.tail.extend(offsets);
+        inner.unsynced += 1;
+
+        if let Some((seq, offset)) = snapshot {
+            // Updates with greater numbers can be written before the snapshot
+            let start = inner
+                .tail
+                .iter()
+                .filter(|(s, _)| *s > seq)
+                .map(|(_, o)| *o)
+                .min()
+                .unwrap_or(offset)
+                .min(offset);
+            inner.tail.retain(|(s, _)| *s > seq);
+            // Index must never point to data that is not on the disk
+            inner.file.sync_data().await?;
+            inner.unsynced = 0;
+            write_index(&self.path, start).await?;
+            inner.start = start;
+        }
+
+        let due = match self.fsync {
+            Fsync::Always => true,
+            Fsync::Every(n) => inner.unsynced >= n,
+            Fsync::Never => false,
+        };
+        if due {
+            inner.file.sync_data().await?;
+            inner.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// Read all records from given offset up to the end of the valid part of the log
+    async fn read_from(&self, start: u64, len: u64) -> Result<Vec<Envelope<St>>, Error> {
+        let mut file = File::open(&self.path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut reader = BufReader::new(file.take(len - start));
+        let mut res = vec![];
+        let mut offset = start;
+        let mut line = vec![];
+        loop {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line).await?;
+            if n == 0 {
+                break;
+            }
+            let record: Record<St, St::Update> =
+                serde_json::from_slice(&line).map_err(|_| Error::Corrupted(offset))?;
+            res.push(record.into_envelope());
+            offset += n as u64;
+        }
+        res.sort_by_key(|e| e.order_key());
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl<St> StateBackend for FileLog<St>
+where
+    St: Clone + State + Serialize + DeserializeOwned + Send + Sync + 'static,
+    St::Update: Serialize + DeserializeOwned + Sync,
+{
+    type State = St;
+    type Err = Error;
+
+    async fn write(&self, upd: Envelope<Self::State>) -> Result<(), Self::Err> {
+        self.append(vec![upd]).await
+    }
+
+    async fn write_batch(&self, upds: Vec<Envelope<Self::State>>) -> Result<(), Self::Err> {
+        self.append(upds).await
+    }
+
+    async fn updates(&self) -> Result<Vec<Envelope<Self::State>>, Self::Err> {
+        let (start, len) = {
+            let inner = self.inner.lock().await;
+            (inner.start, inner.len)
+        };
+        let updates = self.read_from(start, len).await?;
+        // The latest point is always retained
+        Ok(history_until(&updates, HistoryPoint::Seq(u64::MAX)).unwrap_or_default())
+    }
+
+    async fn updates_until(
+        &self,
+        point: HistoryPoint,
+    ) -> Result<Option<Vec<Envelope<Self::State>>>, Self::Err> {
+        let len = self.inner.lock().await.len;
+        let updates = self.read_from(0, len).await?;
+        Ok(history_until(&updates, point))
+    }
+}
+
+/// Result of checking the log on opening
+struct Scanned {
+    /// Length of the log without incomplete records
+    len: u64,
+    /// Sequence numbers and offsets of incremental updates after the latest snapshot
+    tail: Vec<(u64, u64)>,
+}
+
+async fn scan<St>(file: &mut File, start: u64) -> Result<Scanned, Error>
+where
+    St: State + DeserializeOwned,
+    St::Update: DeserializeOwned,
+{
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut reader = BufReader::new(&mut *file);
+    let mut tail = vec![];
+    let mut snapshot_seq = None;
+    let mut offset = start;
+    // Offset of the first record of the current batch and amount of records left in it
+    let mut batch = (start, 0);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line).await?;
+        if n == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        let record: Record<St, St::Update> = match serde_json::from_slice(&line) {
+            Ok(record) => record,
+            Err(_) => {
+                // Only the last line can be broken by a crash
+                let rest = reader.fill_buf().await?;
+                if rest.is_empty() {
+                    break;
+                }
+                return Err(Error::Corrupted(offset));
+            }
+        };
+        if batch.1 == 0 {
+            batch.0 = offset;
+        }
+        batch.1 = record.rest;
+        match record.body {
+            Body::Snapshot(_) => {
+                snapshot_seq = Some(record.seq.max(snapshot_seq.unwrap_or(0)));
+            }
+            Body::Incremental(_) => tail.push((record.seq, offset)),
+        }
+        offset += n as u64;
+    }
+    let len = if batch.1 > 0 { batch.0 } else { offset };
+    tail.retain(|(seq, offset)| *offset < len && snapshot_seq.is_none_or(|s| *seq > s));
+    Ok(Scanned { len, tail })
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".idx");
+    PathBuf::from(name)
+}
+
+/// Replace the index atomically
+async fn write_index(path: &Path, start: u64) -> Result<(), Error> {
+    let index = index_path(path);
+    let mut tmp = index.clone().into_os_string();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp).await?;
+    file.write_all(start.to_string().as_bytes()).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, &index).await?;
+    Ok(())
+}
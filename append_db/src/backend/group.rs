@@ -0,0 +1,165 @@
+pub use crate::backend::class::{Envelope, HistoryPoint, State, StateBackend};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+#[derive(Error, Debug)]
+pub enum Error<E: std::error::Error + 'static> {
+    #[error(transparent)]
+    Backend(E),
+    #[error("Group commit writer is stopped")]
+    Stopped,
+}
+
+/// Updates of a single `write` or `write_batch` call waiting for the group commit
+struct Request<B: StateBackend> {
+    envelopes: Vec<Envelope<B::State>>,
+    done: oneshot::Sender<Result<(), B::Err>>,
+}
+
+/// Backend wrapper that collects writes of concurrent callers and stores them with a
+/// single `StateBackend::write_batch` of the inner backend. A group is written when it
+/// reaches `max_batch` updates or `window` after its first update arrived. Every caller
+/// returns once the whole group is stored. Up to `capacity` writes wait in the queue for
+/// the next group, further callers wait for a free slot.
+///
+/// Batches of `write_batch` callers stay atomic. If the group write fails, requests of
+/// the group are written one by one, so each caller gets the error of its own updates.
+/// That relies on atomic `write_batch` of the inner backend.
+pub struct GroupCommit<B: StateBackend> {
+    pub backend: Arc<B>,
+    queue: mpsc::Sender<Request<B>>,
+}
+
+impl<B: StateBackend> Clone for GroupCommit<B> {
+    fn clone(&self) -> Self {
+        GroupCommit {
+            backend: self.backend.clone(),
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<B: StateBackend + 'static> GroupCommit<B> {
+    /// Start the writer task. It stops when all clones of the wrapper are dropped.
+    pub fn new(
+        backend: B,
+        window: Duration,
+        max_batch: NonZeroUsize,
+        capacity: NonZeroUsize,
+    ) -> Self {
+        let backend = Arc::new(backend);
+        let (queue, requests) = mpsc::channel(capacity.get());
+        tokio::spawn(run(backend.clone(), requests, window, max_batch.get()));
+        GroupCommit { backend, queue }
+    }
+
+    async fn submit(&self, envelopes: Vec<Envelope<B::State>>) -> Result<(), Error<B::Err>> {
+        let (done, result) = oneshot::channel();
+        self.queue
+            .send(Request { envelopes, done })
+            .await
+            .map_err(|_| Error::Stopped)?;
+        match result.await {
+            Ok(res) => res.map_err(Error::Backend),
+            Err(_) => Err(Error::Stopped),
+        }
+    }
+}
+
+/// Body of the writer task
+async fn run<B: StateBackend>(
+    backend: Arc<B>,
+    mut requests: mpsc::Receiver<Request<B>>,
+    window: Duration,
+    max_batch: usize,
+) {
+    while let Some(first) = requests.recv().await {
+        let deadline = Instant::now() + window;
+        let mut size = first.envelopes.len();
+        let mut group = vec![first];
+        while size < max_batch {
+            match tokio::time::timeout_at(deadline, requests.recv()).await {
+                Ok(Some(request)) => {
+                    size += request.envelopes.len();
+                    group.push(request);
+                }
+                _ => break,
+            }
+        }
+        commit(backend.as_ref(), group).await;
+    }
+}
+
+/// Write the group and notify the callers
+async fn commit<B: StateBackend>(backend: &B, mut group: Vec<Request<B>>) {
+    if group.len() == 1 {
+        let request = group.remove(0);
+        let res = backend.write_batch(request.envelopes).await;
+        let _ = request.done.send(res);
+        return;
+    }
+    let envelopes = group
+        .iter()
+        .flat_map(|r| r.envelopes.iter().cloned())
+        .collect();
+    if backend.write_batch(envelopes).await.is_ok() {
+        for request in group {
+            // The caller is not interested in the result anymore
+            let _ = request.done.send(Ok(()));
+        }
+        return;
+    }
+    for request in group {
+        let res = backend.write_batch(request.envelopes).await;
+        let _ = request.done.send(res);
+    }
+}
+
+#[async_trait]
+impl<B: StateBackend + 'static> StateBackend for GroupCommit<B> {
+    type State = B::State;
+    type Err = Error<B::Err>;
+
+    async fn write(&self, upd: Envelope<Self::State>) -> Result<(), Self::Err> {
+        self.submit(vec![upd]).await
+    }
+
+    async fn write_batch(&self, upds: Vec<Envelope<Self::State>>) -> Result<(), Self::Err> {
+        self.submit(upds).await
+    }
+
+    async fn updates(&self) -> Result<Vec<Envelope<Self::State>>, Self::Err> {
+        self.backend.updates().await.map_err(Error::Backend)
+    }
+
+    fn updates_stream(&self) -> BoxStream<'_, Result<Envelope<Self::State>, Self::Err>> {
+        self.backend
+            .updates_stream()
+            .map_err(Error::Backend)
+            .boxed()
+    }
+
+    async fn compact(&self, keep_snapshots: NonZeroUsize) -> Result<u64, Self::Err> {
+        self.backend
+            .compact(keep_snapshots)
+            .await
+            .map_err(Error::Backend)
+    }
+
+    async fn updates_until(
+        &self,
+        point: HistoryPoint,
+    ) -> Result<Option<Vec<Envelope<Self::State>>>, Self::Err> {
+        self.backend
+            .updates_until(point)
+            .await
+            .map_err(Error::Backend)
+    }
+}
@@ -0,0 +1,228 @@
+pub use crate::backend::class::{
+    Envelope, HistoryPoint, Metadata, SnapshotedUpdate, State, StateBackend,
+};
+use crate::backend::memory::history_until;
+use crate::backend::record::Record;
+use async_trait::async_trait;
+use redb::{Database, ReadTransaction, ReadableTable, TableDefinition, TableError};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Storage error: {0}")]
+    Storage(Box<redb::Error>),
+    #[error("Failed to decode/encode JSON: {0}")]
+    Encoding(#[from] serde_json::Error),
+    #[error("Storage task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("Update with sequence number {0} is already stored")]
+    Duplicate(u64),
+}
+
+macro_rules! storage_error {
+    ($($err:ty),*) => {
+        $(impl From<$err> for Error {
+            fn from(e: $err) -> Self {
+                Error::Storage(Box::new(e.into()))
+            }
+        })*
+    };
+}
+
+storage_error!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+/// Table that maps names of state tables to sequence numbers of their latest snapshots
+const SNAPSHOTS: TableDefinition<&str, u64> = TableDefinition::new("append_db_snapshots");
+
+/// Storage in an embedded redb database. Each state gets a table named `State::TABLE`
+/// keyed by big-endian sequence numbers, so loading seeks to the latest snapshot and
+/// scans forward from it. Several states can share the same file with `duplicate`.
+#[derive(Clone)]
+pub struct Redb<St: State> {
+    pub db: Arc<Database>,
+    pub state_proxy: PhantomData<St>,
+}
+
+impl<St: State> Redb<St> {
+    pub fn new(db: Arc<Database>) -> Self {
+        Redb {
+            db,
+            state_proxy: PhantomData,
+        }
+    }
+
+    /// Open or create the database file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Redb::new(Arc::new(Database::create(path)?)))
+    }
+
+    /// Duplicates a handle to the same database, casting St to St2
+    pub fn duplicate<St2: State>(&self) -> Redb<St2> {
+        Redb {
+            db: self.db.clone(),
+            state_proxy: PhantomData,
+        }
+    }
+
+    fn table() -> TableDefinition<'static, &'static [u8], &'static [u8]> {
+        TableDefinition::new(St::TABLE)
+    }
+}
+
+/// Key of the update. Snapshot goes after the incremental update with the same number
+/// to follow `Envelope::order_key`.
+fn key(seq: u64, is_snapshot: bool) -> [u8; 9] {
+    let mut key = [0; 9];
+    key[..8].copy_from_slice(&seq.to_be_bytes());
+    key[8] = is_snapshot as u8;
+    key
+}
+
+fn decode<St>(value: &[u8]) -> Result<Envelope<St>, Error>
+where
+    St: State + DeserializeOwned,
+    St::Update: DeserializeOwned,
+{
+    let record: Record<St, St::Update> = serde_json::from_slice(value)?;
+    Ok(record.into_envelope())
+}
+
+/// Read updates in the given range of keys in ascending order. Missing table is empty.
+fn read_range<St>(
+    txn: &ReadTransaction,
+    range: std::ops::RangeFrom<&[u8]>,
+) -> Result<Vec<Envelope<St>>, Error>
+where
+    St: State + DeserializeOwned,
+    St::Update: DeserializeOwned,
+{
+    let table = match txn.open_table(Redb::<St>::table()) {
+        Ok(table) => table,
+        Err(TableError::TableDoesNotExist(_)) => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut res = vec![];
+    for entry in table.range(range)? {
+        let (_, value) = entry?;
+        res.push(decode(value.value())?);
+    }
+    Ok(res)
+}
+
+#[async_trait]
+impl<St> StateBackend for Redb<St>
+where
+    St: Clone + State + Serialize + DeserializeOwned + Send + Sync + 'static,
+    St::Update: Serialize + DeserializeOwned + Sync,
+{
+    type State = St;
+    type Err = Error;
+
+    async fn write(&self, upd: Envelope<Self::State>) -> Result<(), Self::Err> {
+        self.write_batch(vec![upd]).await
+    }
+
+    async fn write_batch(&self, upds: Vec<Envelope<Self::State>>) -> Result<(), Self::Err> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let txn = db.begin_write()?;
+            let inserted = (|| {
+                let mut table = txn.open_table(Self::table())?;
+                let mut snapshots = txn.open_table(SNAPSHOTS)?;
+                let mut latest = snapshots.get(St::TABLE)?.map(|v| v.value());
+                for upd in upds {
+                    let is_snapshot = upd.update.is_snapshot();
+                    let seq = upd.seq;
+                    let value = serde_json::to_vec(&Record::from_envelope(upd, 0))?;
+                    let key = key(seq, is_snapshot);
+                    // Stored history is never rewritten
+                    if table.insert(key.as_slice(), value.as_slice())?.is_some() {
+                        return Err(Error::Duplicate(seq));
+                    }
+                    if is_snapshot && latest.is_none_or(|s| seq >= s) {
+                        latest = Some(seq);
+                        snapshots.insert(St::TABLE, seq)?;
+                    }
+                }
+                Ok(())
+            })();
+            match inserted {
+                Ok(()) => txn.commit()?,
+                Err(e) => {
+                    txn.abort()?;
+                    return Err(e);
+                }
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn updates(&self) -> Result<Vec<Envelope<Self::State>>, Self::Err> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let txn = db.begin_read()?;
+            let latest = match txn.open_table(SNAPSHOTS) {
+                Ok(snapshots) => snapshots.get(St::TABLE)?.map(|v| v.value()),
+                Err(TableError::TableDoesNotExist(_)) => None,
+                Err(e) => return Err(e.into()),
+            };
+            let start = latest.map_or([0; 9], |seq| key(seq, true));
+            read_range(&txn, start.as_slice()..)
+        })
+        .await?
+    }
+
+    async fn compact(&self, keep_snapshots: NonZeroUsize) -> Result<u64, Self::Err> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let txn = db.begin_write()?;
+            let removed = {
+                let mut table = txn.open_table(Self::table())?;
+                let oldest_kept = table
+                    .iter()?
+                    .rev()
+                    .filter(|entry| !matches!(entry, Ok((k, _)) if k.value()[8] == 0))
+                    .nth(keep_snapshots.get() - 1)
+                    .transpose()?
+                    .map(|(k, _)| k.value().to_vec());
+                match oldest_kept {
+                    Some(cut) => {
+                        let removed = table.range(..cut.as_slice())?.count() as u64;
+                        table.retain_in(..cut.as_slice(), |_, _| false)?;
+                        removed
+                    }
+                    None => 0,
+                }
+            };
+            txn.commit()?;
+            Ok(removed)
+        })
+        .await?
+    }
+
+    async fn updates_until(
+        &self,
+        point: HistoryPoint,
+    ) -> Result<Option<Vec<Envelope<Self::State>>>, Self::Err> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let txn = db.begin_read()?;
+            let updates = read_range::<St>(&txn, [0; 9].as_slice()..)?;
+            Ok(history_until(&updates, point))
+        })
+        .await?
+    }
+}
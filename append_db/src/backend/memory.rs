@@ -1,11 +1,12 @@
-pub use crate::backend::class::{SnapshotedUpdate, State, StateBackend};
+pub use crate::backend::class::{Envelope, HistoryPoint, SnapshotedUpdate, State, StateBackend};
 use async_trait::async_trait;
-use std::{convert::Infallible, sync::Arc};
+use std::{convert::Infallible, num::NonZeroUsize, sync::Arc};
 use tokio::sync::Mutex;
 
 #[derive(Clone)]
 pub struct InMemory<St: State> {
-    pub updates: Arc<Mutex<Vec<SnapshotedUpdate<St>>>>,
+    /// Updates sorted by `Envelope::order_key`
+    pub updates: Arc<Mutex<Vec<Envelope<St>>>>,
 }
 
 impl<St: State> InMemory<St> {
@@ -27,21 +28,91 @@ impl<St: Clone + State + 'static + Send> StateBackend for InMemory<St> {
     type State = St;
     type Err = Infallible;
 
-    async fn write(&self, upd: SnapshotedUpdate<Self::State>) -> Result<(), Self::Err> {
-        self.updates.lock().await.push(upd);
+    async fn write(&self, upd: Envelope<Self::State>) -> Result<(), Self::Err> {
+        insert_ordered(&mut *self.updates.lock().await, upd);
         Ok(())
     }
 
-    async fn updates(&self) -> Result<Vec<SnapshotedUpdate<Self::State>>, Self::Err> {
+    async fn write_batch(&self, upds: Vec<Envelope<Self::State>>) -> Result<(), Self::Err> {
+        let mut updates = self.updates.lock().await;
+        for upd in upds {
+            insert_ordered(&mut updates, upd);
+        }
+        Ok(())
+    }
+
+    async fn updates(&self) -> Result<Vec<Envelope<Self::State>>, Self::Err> {
         let mut res = vec![];
 
         for v in self.updates.lock().await.iter().rev() {
             res.push(v.clone());
-            if v.is_snapshot() {
+            if v.update.is_snapshot() {
                 break;
             }
         }
         res.reverse();
         Ok(res)
     }
+
+    async fn compact(&self, keep_snapshots: NonZeroUsize) -> Result<u64, Self::Err> {
+        let mut updates = self.updates.lock().await;
+        let oldest_kept = updates
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, v)| v.update.is_snapshot())
+            .nth(keep_snapshots.get() - 1)
+            .map(|(i, _)| i);
+        Ok(match oldest_kept {
+            Some(i) => updates.drain(..i).count() as u64,
+            None => 0,
+        })
+    }
+
+    async fn updates_until(
+        &self,
+        point: HistoryPoint,
+    ) -> Result<Option<Vec<Envelope<Self::State>>>, Self::Err> {
+        Ok(history_until(&self.updates.lock().await, point))
+    }
+}
+
+/// Insert update keeping the vector sorted by `Envelope::order_key`
+fn insert_ordered<St: State>(updates: &mut Vec<Envelope<St>>, upd: Envelope<St>) {
+    let key = upd.order_key();
+    let i = updates.partition_point(|v| v.order_key() <= key);
+    updates.insert(i, upd);
+}
+
+/// Pick updates that rebuild state at the given point from updates sorted by
+/// `Envelope::order_key`. See `StateBackend::updates_until`.
+pub(crate) fn history_until<St: State + Clone>(
+    updates: &[Envelope<St>],
+    point: HistoryPoint,
+) -> Option<Vec<Envelope<St>>> {
+    if !updates
+        .first()
+        .is_none_or(|oldest| point.is_retained(oldest))
+    {
+        return None;
+    }
+    let last_seq = match point {
+        HistoryPoint::Seq(seq) => seq,
+        HistoryPoint::Time(t) => updates
+            .iter()
+            .filter(|v| v.created <= t)
+            .map(|v| v.seq)
+            .max()
+            .unwrap_or(0),
+    };
+    let mut res = vec![];
+
+    for v in updates.iter().rev().skip_while(|v| v.seq > last_seq) {
+        res.push(v.clone());
+        if v.update.is_snapshot() {
+            break;
+        }
+    }
+    res.reverse();
+    Some(res)
 }
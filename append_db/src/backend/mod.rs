@@ -1,2 +1,10 @@
+pub mod behind;
 pub mod class;
+#[cfg(feature = "file")]
+pub mod file;
+pub mod group;
+#[cfg(feature = "redb")]
+pub mod kv;
 pub mod memory;
+#[cfg(any(feature = "file", feature = "redb"))]
+mod record;
@@ -0,0 +1,67 @@
+use crate::backend::class::{Envelope, Metadata, SnapshotedUpdate, State};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Serialized form of `Envelope` shared by backends that store updates as JSON
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Record<St, Upd> {
+    pub(crate) seq: u64,
+    pub(crate) created: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) actor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) correlation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) causation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) headers: BTreeMap<String, String>,
+    /// Amount of records of the same batch that follow this one, used by `FileLog`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub(crate) rest: u64,
+    #[serde(flatten)]
+    pub(crate) body: Body<St, Upd>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", content = "body", rename_all = "snake_case")]
+pub(crate) enum Body<St, Upd> {
+    Incremental(Upd),
+    Snapshot(St),
+}
+
+fn is_zero(v: &u64) -> bool {
+    *v == 0
+}
+
+impl<St: State> Record<St, St::Update> {
+    pub(crate) fn from_envelope(envelope: Envelope<St>, rest: u64) -> Self {
+        let meta = envelope.meta;
+        Record {
+            seq: envelope.seq,
+            created: envelope.created,
+            actor: meta.actor,
+            correlation_id: meta.correlation_id,
+            causation_id: meta.causation_id,
+            headers: meta.headers,
+            rest,
+            body: match envelope.update {
+                SnapshotedUpdate::Incremental(upd) => Body::Incremental(upd),
+                SnapshotedUpdate::Snapshot(st) => Body::Snapshot(st),
+            },
+        }
+    }
+
+    pub(crate) fn into_envelope(self) -> Envelope<St> {
+        let update = match self.body {
+            Body::Incremental(upd) => SnapshotedUpdate::Incremental(upd),
+            Body::Snapshot(st) => SnapshotedUpdate::Snapshot(st),
+        };
+        Envelope::with_created(self.seq, self.created, update).with_meta(Metadata {
+            actor: self.actor,
+            correlation_id: self.correlation_id,
+            causation_id: self.causation_id,
+            headers: self.headers,
+        })
+    }
+}
@@ -0,0 +1,90 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Error of encoding or decoding a value by a codec
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Format of update and snapshot bodies in the storage. Backends pick a codec,
+/// while `HasUpdateTag` and `VersionedState` stay independent of it.
+pub trait Codec: Send + Sync + 'static {
+    /// Encoded body as it is passed to the storage
+    type Encoded: Send + Sync;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Self::Encoded, CodecError>;
+
+    fn decode<T: DeserializeOwned>(encoded: &Self::Encoded) -> Result<T, CodecError>;
+
+    /// Human readable form of the encoded body for error messages
+    fn describe(encoded: &Self::Encoded) -> String;
+}
+
+/// Bodies are JSON values. Postgres stores them as `jsonb`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Json;
+
+impl Codec for Json {
+    type Encoded = serde_json::Value;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Self::Encoded, CodecError> {
+        Ok(serde_json::to_value(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(encoded: &Self::Encoded) -> Result<T, CodecError> {
+        Ok(T::deserialize(encoded)?)
+    }
+
+    fn describe(encoded: &Self::Encoded) -> String {
+        encoded.to_string()
+    }
+}
+
+/// Bodies are MessagePack bytes. Postgres stores them as `bytea`.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    type Encoded = Vec<u8>;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Self::Encoded, CodecError> {
+        // Named fields keep structs decodable after reordering of fields
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(encoded: &Self::Encoded) -> Result<T, CodecError> {
+        Ok(rmp_serde::from_slice(encoded)?)
+    }
+
+    fn describe(encoded: &Self::Encoded) -> String {
+        hex(encoded)
+    }
+}
+
+/// Bodies are CBOR bytes. Postgres stores them as `bytea`.
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    type Encoded = Vec<u8>;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Self::Encoded, CodecError> {
+        let mut buf = vec![];
+        ciborium::ser::into_writer(value, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn decode<T: DeserializeOwned>(encoded: &Self::Encoded) -> Result<T, CodecError> {
+        Ok(ciborium::de::from_reader(encoded.as_slice())?)
+    }
+
+    fn describe(encoded: &Self::Encoded) -> String {
+        hex(encoded)
+    }
+}
+
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -1,9 +1,18 @@
-pub use crate::backend::class::{SnapshotedUpdate, State, StateBackend};
+pub use crate::backend::class::{
+    Envelope, HistoryPoint, Metadata, SnapshotedUpdate, State, StateBackend,
+};
+use crate::feed::{Subscription, DEFAULT_FEED_CAPACITY};
+use crate::snapshot::{self, SnapshotPolicy, SnapshotTask};
+use futures::TryStreamExt;
 use std::marker::Sync;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
 use stm::{atomically, TVar};
 use thiserror::Error;
+use tokio::sync::{broadcast, watch, Mutex};
 
-/// We can fail either due state update logic or storage backend failure
+/// We can fail either due state update logic or storage backend failure. Conditional
+/// updates also fail when the state has moved on.
 ///
 /// Note: we cannot use associated types here as it will require 'Debug' impl for
 /// storages.
@@ -13,11 +22,49 @@ pub enum AppendErr<BackErr, UpdErr> {
     Update(UpdErr),
     #[error("Backend: {0}")]
     Backend(BackErr),
+    #[error("State is at version {actual}, but expected {expected}")]
+    VersionMismatch { expected: u64, actual: u64 },
+    #[error("Updates between {last} and {next} are missing")]
+    Gap { last: u64, next: u64 },
+    #[error("Point of history is older than the retained history")]
+    Compacted,
+}
+
+/// Defines the order in which an update reaches the memory and the storage. Wrap the
+/// backend into `backend::behind::WriteBehind` to store updates in the background.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Update is applied in memory first and then written to the storage. If the storage
+    /// write fails, readers have already seen the new state.
+    #[default]
+    MemoryFirst,
+    /// Update is written to the storage first and becomes visible only after the write succeeds.
+    /// Writers are serialized, so a failed write leaves the in memory state untouched.
+    ///
+    /// Don't combine it with `WriteBehind` backend: its writes succeed once queued, so the
+    /// mode only serializes writers.
+    WriteAhead,
 }
 
 pub struct AppendDb<T: StateBackend> {
     pub backend: T,
     pub last_state: TVar<T::State>,
+    /// Sequence number of the last update applied to `last_state`. Zero means
+    /// that no updates were applied to the initial state.
+    pub last_seq: TVar<u64>,
+    /// Sequence number of the last persisted snapshot
+    pub snapshot_seq: TVar<u64>,
+    /// Sum of `State::replay_cost` of updates applied since the last snapshot
+    pub replay_cost: TVar<u64>,
+    pub durability: Durability,
+    /// State the history starts from, used to replay updates when there is no snapshot
+    initial_state: T::State,
+    /// Serializes writers in `Durability::WriteAhead` mode
+    writer: Mutex<()>,
+    /// Change feed of persisted updates
+    feed: broadcast::Sender<Envelope<T::State>>,
+    /// Wakes up background tasks with the last persisted sequence number
+    updated: watch::Sender<u64>,
 }
 
 impl<St: Clone + State + Sync + Send + 'static, Backend: StateBackend<State = St>>
@@ -25,17 +72,80 @@ impl<St: Clone + State + Sync + Send + 'static, Backend: StateBackend<State = St
 {
     /// Initialize with given backend and strarting in memory state
     pub fn new(backend: Backend, initial_state: St) -> Self {
+        Self::with_durability(backend, initial_state, Durability::default())
+    }
+
+    /// Initialize with given backend, starting in memory state and durability mode
+    pub fn with_durability(backend: Backend, initial_state: St, durability: Durability) -> Self {
         AppendDb {
             backend,
-            last_state: TVar::new(initial_state),
+            last_state: TVar::new(initial_state.clone()),
+            last_seq: TVar::new(0),
+            snapshot_seq: TVar::new(0),
+            replay_cost: TVar::new(0),
+            durability,
+            initial_state,
+            writer: Mutex::new(()),
+            feed: broadcast::channel(DEFAULT_FEED_CAPACITY).0,
+            updated: watch::channel(0).0,
         }
     }
 
+    /// Set how many updates are kept for slow subscribers of the change feed
+    pub fn with_feed_capacity(mut self, capacity: usize) -> Self {
+        self.feed = broadcast::channel(capacity).0;
+        self
+    }
+
     /// Access current state
     pub fn get(&self) -> St {
         self.last_state.read_atomic()
     }
 
+    /// Sequence number of the last applied update
+    pub fn seq(&self) -> u64 {
+        self.last_seq.read_atomic()
+    }
+
+    /// Access current state together with sequence number of the last update applied to it
+    pub fn get_versioned(&self) -> (St, u64) {
+        atomically(|trans| Ok((self.last_state.read(trans)?, self.last_seq.read(trans)?)))
+    }
+
+    /// Amount of updates applied since the last persisted snapshot
+    pub fn updates_since_snapshot(&self) -> u64 {
+        atomically(|trans| Ok(self.last_seq.read(trans)? - self.snapshot_seq.read(trans)?))
+    }
+
+    /// Start background task that makes snapshots according to the policy. The task
+    /// stops when the returned handle or the database is dropped.
+    pub fn start_snapshots(self: &Arc<Self>, policy: SnapshotPolicy) -> SnapshotTask
+    where
+        Backend: 'static,
+    {
+        let updated = self.updated.subscribe();
+        SnapshotTask::new(tokio::spawn(snapshot::run(
+            Arc::downgrade(self),
+            updated,
+            policy,
+        )))
+    }
+
+    /// Subscribe to updates that are persisted from now on
+    pub fn subscribe(&self) -> Subscription<St> {
+        Subscription::new(self.feed.subscribe())
+    }
+
+    /// Take current state as the new starting point for the subscriber, that is helpful
+    /// when the subscriber lagged behind. Updates that are already included into the
+    /// returned state will be skipped by the subscription.
+    pub fn resync(&self, subscription: &mut Subscription<St>) -> St {
+        subscription.drain();
+        let (state, seq) = self.get_versioned();
+        subscription.skip_until(seq);
+        state
+    }
+
     /// Access part of state
     pub fn get_with<F, T: Clone>(&self, getter: F) -> T
     where
@@ -50,56 +160,353 @@ impl<St: Clone + State + Sync + Send + 'static, Backend: StateBackend<State = St
 
     /// Write down to storage new update and update in memory version
     pub async fn update(&self, upd: St::Update) -> Result<(), AppendErr<Backend::Err, St::Err>> {
-        atomically(|trans| {
+        self.update_many(vec![upd]).await
+    }
+
+    /// Apply several updates as one unit. Either all of them are applied to the state and
+    /// written to the storage with `StateBackend::write_batch`, or none of them are applied.
+    ///
+    /// Note that in `Durability::MemoryFirst` mode the batch is visible in memory even if the
+    /// storage write fails, the same way as for `update`.
+    pub async fn update_many(
+        &self,
+        upds: Vec<St::Update>,
+    ) -> Result<(), AppendErr<Backend::Err, St::Err>> {
+        self.update_many_meta(upds, Metadata::default()).await
+    }
+
+    /// Same as `update`, but attaches metadata to the update, like the actor who made it
+    pub async fn update_meta(
+        &self,
+        upd: St::Update,
+        meta: Metadata,
+    ) -> Result<(), AppendErr<Backend::Err, St::Err>> {
+        self.update_many_meta(vec![upd], meta).await
+    }
+
+    /// Same as `update_many`, but attaches metadata to every update of the batch
+    pub async fn update_many_meta(
+        &self,
+        upds: Vec<St::Update>,
+        meta: Metadata,
+    ) -> Result<(), AppendErr<Backend::Err, St::Err>> {
+        self.commit(meta, |_, _| Ok((upds.clone(), ()))).await
+    }
+
+    /// Apply the update only if the state is still at the `expected` version, that is
+    /// the sequence number of the last applied update (see `AppendDb::get_versioned`).
+    /// Otherwise fails with `AppendErr::VersionMismatch`.
+    pub async fn update_if(
+        &self,
+        expected: u64,
+        upd: St::Update,
+    ) -> Result<(), AppendErr<Backend::Err, St::Err>> {
+        self.commit(Metadata::default(), |_, seq| {
+            if seq == expected {
+                Ok((vec![upd.clone()], ()))
+            } else {
+                Err(AppendErr::VersionMismatch {
+                    expected,
+                    actual: seq,
+                })
+            }
+        })
+        .await
+    }
+
+    /// Build the update from the current state and apply it atomically, so nobody can
+    /// change the state in between. Errors of `decide` are returned as `AppendErr::Update`.
+    ///
+    /// The closure can be called several times, if the state is changed concurrently.
+    pub async fn update_with<F>(&self, decide: F) -> Result<(), AppendErr<Backend::Err, St::Err>>
+    where
+        F: Fn(&St) -> Result<St::Update, St::Err>,
+    {
+        self.update_with_result(|st| decide(st).map(|upd| (upd, ())))
+            .await
+    }
+
+    /// Same as `update_with`, but `decide` also returns a value for the caller, for
+    /// instance an identifier that is allocated by the update.
+    pub async fn update_with_result<F, R>(
+        &self,
+        decide: F,
+    ) -> Result<R, AppendErr<Backend::Err, St::Err>>
+    where
+        F: Fn(&St) -> Result<(St::Update, R), St::Err>,
+    {
+        self.commit(Metadata::default(), |st, _| match decide(st) {
+            Ok((upd, res)) => Ok((vec![upd], res)),
+            Err(e) => Err(AppendErr::Update(e)),
+        })
+        .await
+    }
+
+    /// Apply updates produced by `decide` from the current state and its version. The
+    /// closure can be called several times, if the state is changed concurrently.
+    async fn commit<F, R>(
+        &self,
+        meta: Metadata,
+        decide: F,
+    ) -> Result<R, AppendErr<Backend::Err, St::Err>>
+    where
+        F: Fn(&St, u64) -> Result<(Vec<St::Update>, R), AppendErr<Backend::Err, St::Err>>,
+    {
+        match self.durability {
+            Durability::MemoryFirst => self.commit_memory_first(meta, decide).await,
+            Durability::WriteAhead => self.commit_write_ahead(meta, decide).await,
+        }
+    }
+
+    async fn commit_memory_first<F, R>(
+        &self,
+        meta: Metadata,
+        decide: F,
+    ) -> Result<R, AppendErr<Backend::Err, St::Err>>
+    where
+        F: Fn(&St, u64) -> Result<(Vec<St::Update>, R), AppendErr<Backend::Err, St::Err>>,
+    {
+        let (seq, upds, res) = atomically(|trans| {
             let mut state = self.last_state.read(trans)?;
-            let upd = state.update(upd.clone()).map_err(AppendErr::Update);
-            match upd {
+            let seq = self.last_seq.read(trans)?;
+            let (upds, res) = match decide(&state, seq) {
+                Ok(decided) => decided,
+                Err(e) => return Ok(Err(e)),
+            };
+            if upds.is_empty() {
+                return Ok(Ok((seq, upds, res)));
+            }
+            match apply_all(&mut state, &upds) {
                 Ok(_) => {
                     self.last_state.write(trans, state)?;
-                    Ok(Ok(()))
+                    self.last_seq.write(trans, seq + upds.len() as u64)?;
+                    self.replay_cost
+                        .modify(trans, |c| c + replay_cost::<St>(&upds))?;
+                    Ok(Ok((seq, upds, res)))
                 }
-                Err(e) => Ok(Err(e)),
+                Err(e) => Ok(Err(AppendErr::Update(e))),
             }
         })?;
-        self.backend
-            .write(SnapshotedUpdate::Incremental(upd))
-            .await
-            .map_err(AppendErr::Backend)?;
-        Ok(())
+        if upds.is_empty() {
+            return Ok(res);
+        }
+        let last_seq = seq + upds.len() as u64;
+        let persisted = self.persist(seq, upds, meta).await?;
+        self.publish(persisted);
+        self.updated.send_replace(last_seq);
+        Ok(res)
+    }
+
+    async fn commit_write_ahead<F, R>(
+        &self,
+        meta: Metadata,
+        decide: F,
+    ) -> Result<R, AppendErr<Backend::Err, St::Err>>
+    where
+        F: Fn(&St, u64) -> Result<(Vec<St::Update>, R), AppendErr<Backend::Err, St::Err>>,
+    {
+        let _guard = self.writer.lock().await;
+        // Nobody else can change the state while we hold the lock, so it is safe to
+        // compute the new state here and commit it after the storage write.
+        let (mut state, seq) = self.get_versioned();
+        let (upds, res) = decide(&state, seq)?;
+        if upds.is_empty() {
+            return Ok(res);
+        }
+        apply_all(&mut state, &upds).map_err(AppendErr::Update)?;
+        let last_seq = seq + upds.len() as u64;
+        let cost = replay_cost::<St>(&upds);
+        let persisted = self.persist(seq, upds, meta).await?;
+        atomically(|trans| {
+            self.last_state.write(trans, state.clone())?;
+            self.last_seq.write(trans, last_seq)?;
+            self.replay_cost.modify(trans, |c| c + cost)
+        });
+        self.publish(persisted);
+        self.updated.send_replace(last_seq);
+        Ok(res)
+    }
+
+    /// Write updates with the same metadata to the storage numbering them right after `seq`. Returns written
+    /// updates if there is anybody to publish them to.
+    async fn persist(
+        &self,
+        seq: u64,
+        upds: Vec<St::Update>,
+        meta: Metadata,
+    ) -> Result<Vec<Envelope<St>>, AppendErr<Backend::Err, St::Err>> {
+        let mut envelopes: Vec<Envelope<St>> = upds
+            .into_iter()
+            .zip(seq + 1..)
+            .map(|(upd, seq)| {
+                Envelope::new(seq, SnapshotedUpdate::Incremental(upd)).with_meta(meta.clone())
+            })
+            .collect();
+        let persisted = self.feed_copy(&envelopes);
+        let res = if envelopes.len() == 1 {
+            self.backend.write(envelopes.remove(0)).await
+        } else {
+            self.backend.write_batch(envelopes).await
+        };
+        res.map_err(AppendErr::Backend)?;
+        Ok(persisted)
+    }
+
+    /// Copy updates for the change feed only if there are subscribers
+    fn feed_copy(&self, envelopes: &[Envelope<St>]) -> Vec<Envelope<St>> {
+        if self.feed.receiver_count() > 0 {
+            envelopes.to_vec()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Send persisted updates to the subscribers
+    fn publish(&self, envelopes: Vec<Envelope<St>>) {
+        for envelope in envelopes {
+            // Error means that there are no subscribers at the moment
+            let _ = self.feed.send(envelope);
+        }
     }
 
     /// Write down snapshot for current state
     pub async fn snapshot(&self) -> Result<(), AppendErr<Backend::Err, St::Err>> {
-        let state = atomically(|trans| self.last_state.read(trans));
+        let (state, seq, cost) = atomically(|trans| {
+            Ok((
+                self.last_state.read(trans)?,
+                self.last_seq.read(trans)?,
+                self.replay_cost.read(trans)?,
+            ))
+        });
+        let envelope = Envelope::new(seq, SnapshotedUpdate::Snapshot(state));
+        let persisted = self.feed_copy(std::slice::from_ref(&envelope));
         self.backend
-            .write(SnapshotedUpdate::Snapshot(state))
+            .write(envelope)
             .await
             .map_err(AppendErr::Backend)?;
+        // Updates that came after we read the state are still to be replayed
+        atomically(|trans| {
+            self.replay_cost.modify(trans, |c| c.saturating_sub(cost))?;
+            self.snapshot_seq.modify(trans, |s| s.max(seq))
+        });
+        self.publish(persisted);
         Ok(())
     }
 
+    /// Rebuild state as it was at the given point of history from the nearest earlier
+    /// snapshot and updates after it. The current in memory state is not touched.
+    /// Fails with `AppendErr::Compacted` for points removed by `compact`.
+    pub async fn state_at(
+        &self,
+        point: HistoryPoint,
+    ) -> Result<St, AppendErr<Backend::Err, St::Err>> {
+        let updates = self
+            .backend
+            .updates_until(point)
+            .await
+            .map_err(AppendErr::Backend)?
+            .ok_or(AppendErr::Compacted)?;
+        let mut state = self.initial_state.clone();
+        for upd in updates {
+            match upd.update {
+                SnapshotedUpdate::Snapshot(s) => state = s,
+                SnapshotedUpdate::Incremental(upd) => {
+                    state.update(upd).map_err(AppendErr::Update)?
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    /// Remove history older than `keep_snapshots` most recent snapshots from the storage.
+    /// Returns amount of removed updates. `state_at` can't rebuild the removed points
+    /// afterwards. See `StateBackend::compact`.
+    pub async fn compact(
+        &self,
+        keep_snapshots: NonZeroUsize,
+    ) -> Result<u64, AppendErr<Backend::Err, St::Err>> {
+        self.backend
+            .compact(keep_snapshots)
+            .await
+            .map_err(AppendErr::Backend)
+    }
+
     /// Load state from storage
     pub async fn load(&self) -> Result<(), AppendErr<Backend::Err, St::Err>> {
-        let updates = self.backend.updates().await.map_err(AppendErr::Backend)?;
+        self.load_patched(|state, _| state).await
+    }
 
-        let (mut state, start_index) = match updates.first() {
-            Some(SnapshotedUpdate::Snapshot(s)) => (s.clone(), 1),
-            _ => {
-                let state = atomically(|trans| self.last_state.read(trans));
-                (state, 0)
-            }
-        };
+    /// Load state from storage starting from the initial state instead of the current one.
+    /// Readers see the current state until the whole history is replayed.
+    ///
+    /// Unlike `load`, replay stops before the first missing update, unless a later snapshot
+    /// covers it, as the update may belong to a transaction that is not committed yet.
+    /// Updates after the gap can be applied later with `apply_persisted`.
+    pub async fn reload(&self) -> Result<(), AppendErr<Backend::Err, St::Err>> {
+        self.load_from(|state, _| state, Replay::Committed).await
+    }
 
-        for upd in &updates[start_index..] {
-            match upd {
-                SnapshotedUpdate::Snapshot(s) => state = s.clone(),
-                SnapshotedUpdate::Incremental(upd) => {
-                    state.update(upd.clone()).map_err(AppendErr::Update)?
+    /// Load state from storage starting from the initial state like `reload`, but apply
+    /// updates after missing ones as a fresh `load` does. Use it when the missing updates
+    /// are known to be lost.
+    pub async fn reload_all(&self) -> Result<(), AppendErr<Backend::Err, St::Err>> {
+        self.load_from(|state, _| state, Replay::Initial).await
+    }
+
+    /// Apply updates that were persisted by another writer of the same storage, for
+    /// instance the leader of a follower. Updates that are already applied are skipped, a
+    /// snapshot replaces the state. Fails with `AppendErr::Gap` without touching the
+    /// state if some updates are missing between the current state and the given ones.
+    ///
+    /// Applied updates are published to the change feed.
+    pub async fn apply_persisted(
+        &self,
+        mut envelopes: Vec<Envelope<St>>,
+    ) -> Result<(), AppendErr<Backend::Err, St::Err>> {
+        envelopes.sort_by_key(|e| e.order_key());
+        let _guard = self.writer.lock().await;
+        let (mut state, mut seq) = self.get_versioned();
+        let mut snapshot = None;
+        let mut cost = 0;
+        let mut applied = vec![];
+        for envelope in envelopes {
+            match &envelope.update {
+                SnapshotedUpdate::Snapshot(s) if envelope.seq >= seq => {
+                    state = s.clone();
+                    snapshot = Some(envelope.seq);
+                    cost = 0;
+                }
+                SnapshotedUpdate::Incremental(upd) if envelope.seq == seq + 1 => {
+                    cost += St::replay_cost(upd);
+                    state.update(upd.clone()).map_err(AppendErr::Update)?;
+                }
+                _ if envelope.seq <= seq => continue,
+                _ => {
+                    return Err(AppendErr::Gap {
+                        last: seq,
+                        next: envelope.seq,
+                    })
                 }
             }
+            seq = envelope.seq;
+            applied.push(envelope);
         }
-        atomically(|trans| self.last_state.write(trans, state.clone()));
-
+        if applied.is_empty() {
+            return Ok(());
+        }
+        atomically(|trans| {
+            self.last_state.write(trans, state.clone())?;
+            self.last_seq.write(trans, seq)?;
+            match snapshot {
+                Some(snapshot) => {
+                    self.snapshot_seq.write(trans, snapshot)?;
+                    self.replay_cost.write(trans, cost)
+                }
+                None => self.replay_cost.modify(trans, |c| c + cost),
+            }
+        });
+        self.publish(applied);
+        self.updated.send_replace(seq);
         Ok(())
     }
 
@@ -108,6 +515,8 @@ impl<St: Clone + State + Sync + Send + 'static, Backend: StateBackend<State = St
     ///
     /// The second parameter in the closure indicates whether the patching occurs at start or not.
     /// For later snapshots it will be called with false.
+    ///
+    /// Updates are consumed from `StateBackend::updates_stream` one by one.
     pub async fn load_patched<F>(
         &self,
         patch_state: F,
@@ -115,26 +524,87 @@ impl<St: Clone + State + Sync + Send + 'static, Backend: StateBackend<State = St
     where
         F: Copy + FnOnce(St, bool) -> St,
     {
-        let updates = self.backend.updates().await.map_err(AppendErr::Backend)?;
+        self.load_from(patch_state, Replay::Current).await
+    }
 
-        let (mut state, start_index) = match updates.first() {
-            Some(SnapshotedUpdate::Snapshot(s)) => (patch_state(s.clone(), true), 1),
-            _ => {
-                let state = atomically(|trans| self.last_state.read(trans));
-                (patch_state(state, true), 0)
-            }
-        };
+    /// Replay the storage on top of the current state or from the initial one
+    async fn load_from<F>(
+        &self,
+        patch_state: F,
+        replay: Replay,
+    ) -> Result<(), AppendErr<Backend::Err, St::Err>>
+    where
+        F: Copy + FnOnce(St, bool) -> St,
+    {
+        let _guard = self.writer.lock().await;
+        let mut updates = self.backend.updates_stream();
 
-        for upd in &updates[start_index..] {
-            match upd {
-                SnapshotedUpdate::Snapshot(s) => state = patch_state(s.clone(), false),
+        let (start, mut seq) = match replay {
+            Replay::Current => self.get_versioned(),
+            Replay::Initial | Replay::Committed => (self.initial_state.clone(), 0),
+        };
+        let mut state: Option<St> = None;
+        let mut snapshot_seq = 0;
+        let mut cost = 0;
+        // Updates after a gap are skipped until the next snapshot
+        let mut gap = false;
+        while let Some(upd) = updates.try_next().await.map_err(AppendErr::Backend)? {
+            if replay == Replay::Committed
+                && !upd.update.is_snapshot()
+                && (gap || upd.seq != seq + 1)
+            {
+                gap = gap || upd.seq > seq;
+                continue;
+            }
+            seq = upd.seq;
+            match upd.update {
+                SnapshotedUpdate::Snapshot(s) => {
+                    state = Some(patch_state(s, state.is_none()));
+                    snapshot_seq = upd.seq;
+                    cost = 0;
+                    gap = false;
+                }
                 SnapshotedUpdate::Incremental(upd) => {
-                    state.update(upd.clone()).map_err(AppendErr::Update)?
+                    cost += St::replay_cost(&upd);
+                    state
+                        .get_or_insert_with(|| patch_state(start.clone(), true))
+                        .update(upd)
+                        .map_err(AppendErr::Update)?;
                 }
             }
         }
-        atomically(|trans| self.last_state.write(trans, state.clone()));
+        let state = state.unwrap_or_else(|| patch_state(start.clone(), true));
+        atomically(|trans| {
+            self.last_state.write(trans, state.clone())?;
+            self.last_seq.write(trans, seq)?;
+            self.snapshot_seq.write(trans, snapshot_seq)?;
+            self.replay_cost.write(trans, cost)
+        });
 
         Ok(())
     }
 }
+
+/// Starting point of `AppendDb::load_from`
+#[derive(Clone, Copy, PartialEq)]
+enum Replay {
+    /// On top of the current state
+    Current,
+    /// From the initial state
+    Initial,
+    /// From the initial state up to the first missing update
+    Committed,
+}
+
+/// Apply updates one by one stopping at the first failure
+fn apply_all<St: State>(state: &mut St, upds: &[St::Update]) -> Result<(), St::Err> {
+    for upd in upds {
+        state.update(upd.clone())?;
+    }
+    Ok(())
+}
+
+/// Total replay cost of the updates
+fn replay_cost<St: State>(upds: &[St::Update]) -> u64 {
+    upds.iter().map(St::replay_cost).sum()
+}
@@ -0,0 +1,64 @@
+use crate::backend::class::{Envelope, State};
+use thiserror::Error;
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+
+/// Default amount of updates kept for slow subscribers before they start lagging
+pub const DEFAULT_FEED_CAPACITY: usize = 1024;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum FeedErr {
+    /// Subscriber was too slow and missed given amount of updates. The subscriber
+    /// should resync with `AppendDb::resync` before continuing.
+    #[error("Subscriber lagged behind and missed {0} updates")]
+    Lagged(u64),
+    /// The database was dropped and no more updates will come
+    #[error("Feed is closed")]
+    Closed,
+}
+
+/// Receiving end of the change feed of `AppendDb`. Yields updates after
+/// they are persisted by the storage backend, including snapshots.
+///
+/// Note: in `Durability::MemoryFirst` mode concurrent updates can be persisted and
+/// therefore delivered not in the order of their sequence numbers.
+pub struct Subscription<St: State> {
+    receiver: broadcast::Receiver<Envelope<St>>,
+    /// Updates with sequence number up to this one are already seen by the subscriber
+    synced: u64,
+}
+
+impl<St: State + Clone> Subscription<St> {
+    pub(crate) fn new(receiver: broadcast::Receiver<Envelope<St>>) -> Self {
+        Subscription {
+            receiver,
+            synced: 0,
+        }
+    }
+
+    /// Wait for the next persisted update
+    pub async fn recv(&mut self) -> Result<Envelope<St>, FeedErr> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(envelope) if envelope.seq <= self.synced => continue,
+                Ok(envelope) => return Ok(envelope),
+                Err(RecvError::Lagged(n)) => return Err(FeedErr::Lagged(n)),
+                Err(RecvError::Closed) => return Err(FeedErr::Closed),
+            }
+        }
+    }
+
+    /// Drop all updates that are already delivered to the feed. Every delivered update
+    /// is already applied to the in memory state, so it is safe to do before resync.
+    pub(crate) fn drain(&mut self) {
+        while let Ok(_) | Err(TryRecvError::Lagged(_)) = self.receiver.try_recv() {}
+    }
+
+    /// Skip all updates with sequence number up to given one. They are
+    /// already included into a state the subscriber resynced with.
+    pub fn skip_until(&mut self, seq: u64) {
+        self.synced = seq;
+    }
+}
@@ -1,89 +1,1106 @@
 pub mod backend;
+pub mod codec;
 pub mod db;
+pub mod feed;
+pub mod snapshot;
+pub mod update;
 
+#[cfg(feature = "derive")]
+pub use append_db_postgres_derive::*;
 pub use backend::class::*;
+pub use codec::Codec;
+#[doc(hidden)]
+pub use serde;
+pub use update::{HasUpdateTag, VersionedState};
 
 #[cfg(test)]
 mod tests {
-    use super::backend::class::{SnapshotedUpdate, State, StateBackend};
+    use super::backend::behind::{self, FailurePolicy, WriteBehind};
+    use super::backend::class::{
+        Envelope, HistoryPoint, Metadata, SnapshotedUpdate, State, StateBackend,
+    };
+    use super::backend::group::{self, GroupCommit};
     use super::backend::memory::InMemory;
-    use super::db::AppendDb;
-    use std::convert::Infallible;
-    use std::ops::Deref;
+    use super::db::{AppendDb, AppendErr, Durability};
+    use super::feed::FeedErr;
+    use super::snapshot::SnapshotPolicy;
+    use async_trait::async_trait;
+    use chrono::Utc;
+    use futures::TryStreamExt;
+    use std::io;
+    use std::num::NonZeroUsize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use thiserror::Error;
 
-    #[derive(Clone, Debug, PartialEq)]
+    #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
     struct State0 {
         field: u64,
     }
 
-    #[derive(Clone, Debug, PartialEq)]
+    #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
     enum Update0 {
         Add(u64),
         Set(u64),
+        Sub(u64),
     }
 
+    #[derive(Debug, Error)]
+    #[error("Field underflow")]
+    struct Underflow;
+
     impl State for State0 {
         type Update = Update0;
-        type Err = Infallible;
+        type Err = Underflow;
         const TABLE: &'static str = "updates";
 
         fn update(&mut self, upd: Update0) -> Result<(), Self::Err> {
             match upd {
                 Update0::Add(v) => self.field += v,
                 Update0::Set(v) => self.field = v,
+                Update0::Sub(v) => self.field = self.field.checked_sub(v).ok_or(Underflow)?,
             }
             Ok(())
         }
     }
 
+    /// Drop timestamps to compare stored updates
+    fn stripped(upds: Vec<Envelope<State0>>) -> Vec<(u64, SnapshotedUpdate<State0>)> {
+        upds.into_iter().map(|e| (e.seq, e.update)).collect()
+    }
+
+    /// Backend that refuses every write
+    struct Failing;
+
+    #[async_trait]
+    impl StateBackend for Failing {
+        type State = State0;
+        type Err = io::Error;
+
+        async fn write(&self, _: Envelope<State0>) -> Result<(), Self::Err> {
+            Err(io::Error::other("write failed"))
+        }
+
+        async fn updates(&self) -> Result<Vec<Envelope<State0>>, Self::Err> {
+            Ok(vec![])
+        }
+    }
+
+    /// In memory backend that counts batches and rejects batches with `Update0::Set(0)`
+    /// and the given amount of the next batches
+    #[derive(Clone, Default)]
+    struct Batches {
+        inner: InMemory<State0>,
+        batches: Arc<AtomicUsize>,
+        failures: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl StateBackend for Batches {
+        type State = State0;
+        type Err = io::Error;
+
+        async fn write(&self, upd: Envelope<State0>) -> Result<(), Self::Err> {
+            self.write_batch(vec![upd]).await
+        }
+
+        async fn write_batch(&self, upds: Vec<Envelope<State0>>) -> Result<(), Self::Err> {
+            self.batches.fetch_add(1, Ordering::SeqCst);
+            let rejected = SnapshotedUpdate::Incremental(Update0::Set(0));
+            let failing = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if failing || upds.iter().any(|e| e.update == rejected) {
+                return Err(io::Error::other("rejected"));
+            }
+            self.inner.write_batch(upds).await.expect("infallible");
+            Ok(())
+        }
+
+        async fn updates(&self) -> Result<Vec<Envelope<State0>>, Self::Err> {
+            Ok(self.inner.updates().await.expect("infallible"))
+        }
+    }
+
     #[tokio::test]
     async fn in_memory_init() {
         let state0 = State0 { field: 42 };
         let db = AppendDb::new(InMemory::new(), state0.clone());
-        assert_eq!(db.get().await.deref(), &state0);
+        assert_eq!(db.get(), state0);
     }
 
     #[tokio::test]
     async fn in_memory_updates() {
         let state0 = State0 { field: 42 };
-        let mut db = AppendDb::new(InMemory::new(), state0);
+        let db = AppendDb::new(InMemory::new(), state0);
         db.update(Update0::Add(1)).await.expect("update");
-        assert_eq!(db.get().await.deref().field, 43);
+        assert_eq!(db.get().field, 43);
         db.update(Update0::Set(4)).await.expect("update");
-        assert_eq!(db.get().await.deref().field, 4);
+        assert_eq!(db.get().field, 4);
     }
 
     #[tokio::test]
     async fn in_memory_snapshot() {
         let state0 = State0 { field: 42 };
-        let mut db = AppendDb::new(InMemory::new(), state0);
+        let db = AppendDb::new(InMemory::new(), state0);
         db.update(Update0::Add(1)).await.expect("update");
         db.snapshot().await.expect("snapshot");
 
         let upds = db.backend.updates().await.expect("collected");
-        assert_eq!(upds, vec![SnapshotedUpdate::Snapshot(State0 { field: 43 })])
+        assert_eq!(
+            stripped(upds),
+            vec![(1, SnapshotedUpdate::Snapshot(State0 { field: 43 }))]
+        )
     }
 
     #[tokio::test]
     async fn in_memory_reconstruct() {
         let state0 = State0 { field: 42 };
-        let mut db = AppendDb::new(InMemory::new(), state0);
+        let db = AppendDb::new(InMemory::new(), state0);
         db.update(Update0::Add(1)).await.expect("update");
         db.update(Update0::Set(4)).await.expect("update");
 
         db.load().await.expect("load");
-        assert_eq!(db.get().await.deref().field, 4);
+        assert_eq!(db.get().field, 4);
     }
 
     #[tokio::test]
     async fn in_memory_reconstruct_snapshot() {
         let state0 = State0 { field: 42 };
-        let mut db = AppendDb::new(InMemory::new(), state0);
+        let db = AppendDb::new(InMemory::new(), state0);
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Set(4)).await.expect("update");
+
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 4);
+    }
+
+    #[tokio::test]
+    async fn in_memory_sequence() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0.clone());
         db.update(Update0::Add(1)).await.expect("update");
         db.snapshot().await.expect("snapshot");
         db.update(Update0::Set(4)).await.expect("update");
+        assert_eq!(db.seq(), 2);
 
+        let db = AppendDb::new(db.backend, state0);
         db.load().await.expect("load");
-        assert_eq!(db.get().await.deref().field, 4);
+        assert_eq!(db.seq(), 2);
+        db.update(Update0::Add(1)).await.expect("update");
+        assert_eq!(db.seq(), 3);
+    }
+
+    #[tokio::test]
+    async fn in_memory_out_of_order_writes() {
+        let backend = InMemory::new();
+        backend
+            .write(Envelope::new(
+                2,
+                SnapshotedUpdate::Incremental(Update0::Add(1)),
+            ))
+            .await
+            .expect("write");
+        backend
+            .write(Envelope::new(
+                1,
+                SnapshotedUpdate::Incremental(Update0::Set(4)),
+            ))
+            .await
+            .expect("write");
+
+        let db = AppendDb::new(backend, State0 { field: 42 });
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 5);
+        assert_eq!(db.seq(), 2);
+    }
+
+    #[tokio::test]
+    async fn in_memory_updates_stream() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0);
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Set(4)).await.expect("update");
+
+        let upds: Vec<_> = db
+            .backend
+            .updates_stream()
+            .try_collect()
+            .await
+            .expect("collected");
+        assert_eq!(
+            stripped(upds),
+            vec![
+                (1, SnapshotedUpdate::Snapshot(State0 { field: 43 })),
+                (2, SnapshotedUpdate::Incremental(Update0::Set(4))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn in_memory_compact() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0.clone());
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let keep = |n| NonZeroUsize::new(n).expect("non zero");
+        assert_eq!(db.compact(keep(3)).await.expect("compact"), 0);
+        assert_eq!(db.compact(keep(2)).await.expect("compact"), 1);
+        assert_eq!(db.compact(keep(1)).await.expect("compact"), 2);
+        assert_eq!(db.backend.updates.lock().await.len(), 2);
+
+        let db = AppendDb::new(db.backend, state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 45);
+        assert_eq!(db.seq(), 3);
+    }
+
+    #[tokio::test]
+    async fn in_memory_state_at_compacted() {
+        let db = AppendDb::new(InMemory::new(), State0 { field: 42 });
+        let before = Utc::now();
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+        let at = |point| db.state_at(point);
+        assert_eq!(
+            at(HistoryPoint::Time(before)).await.expect("state").field,
+            42
+        );
+
+        db.compact(NonZeroUsize::new(1).expect("non zero"))
+            .await
+            .expect("compact");
+        for point in [
+            HistoryPoint::Seq(0),
+            HistoryPoint::Seq(1),
+            HistoryPoint::Time(before),
+        ] {
+            assert!(matches!(at(point).await, Err(AppendErr::Compacted)));
+        }
+        assert_eq!(at(HistoryPoint::Seq(2)).await.expect("state").field, 44);
+        assert_eq!(at(HistoryPoint::Seq(3)).await.expect("state").field, 45);
+    }
+
+    #[tokio::test]
+    async fn in_memory_update_meta() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0);
+        let meta = Metadata::default()
+            .with_actor("operator")
+            .with_correlation_id("request-1")
+            .with_header("ip", "127.0.0.1");
+        db.update_meta(Update0::Add(1), meta.clone())
+            .await
+            .expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let upds = db.backend.updates().await.expect("collected");
+        assert_eq!(upds[0].meta, meta);
+        assert_eq!(upds[1].meta, Metadata::default());
+    }
+
+    #[tokio::test]
+    async fn in_memory_update_many() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0);
+        db.update_many(vec![Update0::Set(4), Update0::Add(1)])
+            .await
+            .expect("update");
+        assert_eq!(db.get().field, 5);
+        assert_eq!(db.seq(), 2);
+
+        let upds = db.backend.updates().await.expect("collected");
+        assert_eq!(
+            stripped(upds),
+            vec![
+                (1, SnapshotedUpdate::Incremental(Update0::Set(4))),
+                (2, SnapshotedUpdate::Incremental(Update0::Add(1))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn in_memory_update_many_failed() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0.clone());
+        let res = db
+            .update_many(vec![Update0::Set(4), Update0::Sub(5), Update0::Add(1)])
+            .await;
+        assert!(matches!(res, Err(AppendErr::Update(Underflow))));
+        assert_eq!(db.get(), state0);
+        assert_eq!(db.seq(), 0);
+        assert_eq!(
+            stripped(db.backend.updates().await.expect("collected")),
+            vec![]
+        );
+    }
+
+    #[tokio::test]
+    async fn in_memory_apply_persisted() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0.clone());
+        let incremental = |seq, upd| Envelope::new(seq, SnapshotedUpdate::Incremental(upd));
+        db.apply_persisted(vec![
+            incremental(2, Update0::Add(1)),
+            incremental(1, Update0::Set(4)),
+        ])
+        .await
+        .expect("apply");
+        assert_eq!(db.get_versioned(), (State0 { field: 5 }, 2));
+
+        let mut sub = db.subscribe();
+        let res = db
+            .apply_persisted(vec![
+                incremental(2, Update0::Add(1)),
+                incremental(3, Update0::Add(1)),
+                incremental(5, Update0::Add(1)),
+            ])
+            .await;
+        assert!(matches!(res, Err(AppendErr::Gap { last: 3, next: 5 })));
+        assert_eq!(db.get_versioned(), (State0 { field: 5 }, 2));
+
+        db.apply_persisted(vec![
+            incremental(3, Update0::Add(1)),
+            Envelope::new(5, SnapshotedUpdate::Snapshot(State0 { field: 10 })),
+        ])
+        .await
+        .expect("apply");
+        assert_eq!(db.get_versioned(), (State0 { field: 10 }, 5));
+        assert_eq!(db.updates_since_snapshot(), 0);
+        assert_eq!(sub.recv().await.map(|e| e.seq), Ok(3));
+        assert_eq!(sub.recv().await.map(|e| e.seq), Ok(5));
+    }
+
+    #[tokio::test]
+    async fn in_memory_reload() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0);
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        db.reload().await.expect("reload");
+        assert_eq!(db.get_versioned(), (State0 { field: 44 }, 2));
+    }
+
+    #[tokio::test]
+    async fn in_memory_reload_gap() {
+        let db = AppendDb::new(InMemory::new(), State0 { field: 42 });
+        let incremental = |seq, upd| Envelope::new(seq, SnapshotedUpdate::Incremental(upd));
+        db.backend
+            .write_batch(vec![
+                incremental(1, Update0::Add(1)),
+                incremental(3, Update0::Add(1)),
+            ])
+            .await
+            .expect("write");
+        db.reload().await.expect("reload");
+        assert_eq!(db.get_versioned(), (State0 { field: 43 }, 1));
+        db.reload_all().await.expect("reload");
+        assert_eq!(db.get_versioned(), (State0 { field: 44 }, 3));
+
+        // Snapshot covers the missing update
+        db.backend
+            .write_batch(vec![
+                Envelope::new(3, SnapshotedUpdate::Snapshot(State0 { field: 100 })),
+                incremental(4, Update0::Add(1)),
+            ])
+            .await
+            .expect("write");
+        db.reload().await.expect("reload");
+        assert_eq!(db.get_versioned(), (State0 { field: 101 }, 4));
+    }
+
+    #[tokio::test]
+    async fn default_updates_until() {
+        // `Batches` relies on the default implementation that sees only `updates`
+        let db = AppendDb::new(Batches::default(), State0 { field: 42 });
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let at = |seq| db.state_at(HistoryPoint::Seq(seq));
+        assert_eq!(at(2).await.expect("state").field, 44);
+        assert_eq!(at(1).await.expect("state").field, 43);
+        assert!(matches!(at(0).await, Err(AppendErr::Compacted)));
+    }
+
+    #[tokio::test]
+    async fn group_commit() {
+        let backend = Batches::default();
+        let group = GroupCommit::new(
+            backend.clone(),
+            Duration::from_millis(50),
+            NonZeroUsize::new(100).expect("non zero"),
+            NonZeroUsize::new(16).expect("non zero"),
+        );
+        let db = AppendDb::new(group, State0 { field: 0 });
+        let updates = (1..=10).map(|v| db.update(Update0::Add(v)));
+        for res in futures::future::join_all(updates).await {
+            res.expect("update");
+        }
+        db.update_many(vec![Update0::Add(1), Update0::Add(1)])
+            .await
+            .expect("update");
+        assert!(backend.batches.load(Ordering::SeqCst) < 11);
+
+        let upds = stripped(backend.updates().await.expect("collected"));
+        assert_eq!(upds.len(), 12);
+        assert!(upds.iter().map(|(seq, _)| *seq).eq(1..=12));
+        let db = AppendDb::new(backend, State0 { field: 0 });
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 57);
+    }
+
+    #[tokio::test]
+    async fn group_commit_errors() {
+        let backend = Batches::default();
+        let group = GroupCommit::new(
+            backend.clone(),
+            Duration::from_millis(50),
+            NonZeroUsize::new(100).expect("non zero"),
+            NonZeroUsize::new(16).expect("non zero"),
+        );
+        let db = AppendDb::new(group, State0 { field: 0 });
+        let (first, rejected, last) = tokio::join!(
+            db.update(Update0::Add(1)),
+            db.update(Update0::Set(0)),
+            db.update(Update0::Add(2)),
+        );
+        first.expect("update");
+        last.expect("update");
+        assert!(matches!(
+            rejected,
+            Err(AppendErr::Backend(group::Error::Backend(_)))
+        ));
+        // The group and then every request on its own
+        assert_eq!(backend.batches.load(Ordering::SeqCst), 4);
+        assert_eq!(
+            stripped(backend.updates().await.expect("collected")),
+            vec![
+                (1, SnapshotedUpdate::Incremental(Update0::Add(1))),
+                (3, SnapshotedUpdate::Incremental(Update0::Add(2))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn write_behind_retry() {
+        let backend = Batches::default();
+        backend.failures.store(2, Ordering::SeqCst);
+        let policy = FailurePolicy::Retry {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(4),
+        };
+        let capacity = NonZeroUsize::new(16).expect("non zero");
+        let db = AppendDb::new(
+            WriteBehind::new(backend.clone(), capacity, policy),
+            State0 { field: 0 },
+        );
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Add(2)).await.expect("update");
+        assert_eq!(db.get().field, 3);
+
+        // Reads wait for the queued writes
+        db.reload().await.expect("reload");
+        assert_eq!(db.get_versioned(), (State0 { field: 3 }, 2));
+        db.backend.wait_durable(2).await.expect("durable");
+        db.backend.flush().await.expect("flush");
+        assert_eq!(db.backend.durable_seq(), 2);
+        assert!(backend.batches.load(Ordering::SeqCst) >= 3);
+        assert_eq!(
+            stripped(backend.updates().await.expect("collected")),
+            vec![
+                (1, SnapshotedUpdate::Incremental(Update0::Add(1))),
+                (2, SnapshotedUpdate::Incremental(Update0::Add(2))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn write_behind_halt() {
+        let capacity = NonZeroUsize::new(16).expect("non zero");
+        let db = AppendDb::new(
+            WriteBehind::new(Batches::default(), capacity, FailurePolicy::Halt),
+            State0 { field: 42 },
+        );
+        // Applied in memory, the failure is reported later
+        db.update(Update0::Set(0)).await.expect("update");
+        assert_eq!(db.get().field, 0);
+        let res = db.backend.flush().await;
+        assert!(matches!(res, Err(behind::Error::Halted(_))));
+        let res = db.update(Update0::Add(1)).await;
+        assert!(matches!(
+            res,
+            Err(AppendErr::Backend(behind::Error::Halted(_)))
+        ));
+        assert!(db.backend.wait_durable(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn write_behind_callback() {
+        let backend = Batches::default();
+        let failed = Arc::new(std::sync::Mutex::new(vec![]));
+        let reported = failed.clone();
+        let policy = FailurePolicy::Callback(Box::new(move |upds: Vec<Envelope<State0>>, _| {
+            reported.lock().expect("not poisoned").extend(upds);
+        }));
+        let capacity = NonZeroUsize::new(16).expect("non zero");
+        let db = AppendDb::new(
+            WriteBehind::new(backend.clone(), capacity, policy),
+            State0 { field: 42 },
+        );
+        db.update(Update0::Set(0)).await.expect("update");
+        let res = db.backend.wait_durable(1).await;
+        assert!(matches!(res, Err(behind::Error::Dropped(seqs)) if seqs == [1]));
+        // Reported drops are not reported again
+        db.update(Update0::Add(1)).await.expect("update");
+        db.backend.flush().await.expect("flush");
+        db.backend.wait_durable(2).await.expect("durable");
+
+        db.update(Update0::Set(0)).await.expect("update");
+        let res = db.backend.flush().await;
+        assert!(matches!(res, Err(behind::Error::Dropped(seqs)) if seqs == [3]));
+        db.backend.flush().await.expect("flush");
+        db.backend.wait_durable(3).await.expect("durable");
+
+        let failed = failed.lock().expect("not poisoned").clone();
+        assert_eq!(
+            stripped(failed),
+            vec![
+                (1, SnapshotedUpdate::Incremental(Update0::Set(0))),
+                (3, SnapshotedUpdate::Incremental(Update0::Set(0)))
+            ]
+        );
+        assert_eq!(
+            stripped(backend.updates().await.expect("collected")),
+            vec![(2, SnapshotedUpdate::Incremental(Update0::Add(1)))]
+        );
+    }
+
+    #[tokio::test]
+    async fn in_memory_subscribe() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0);
+        let mut sub = db.subscribe();
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+
+        assert_eq!(
+            sub.recv().await.map(|e| (e.seq, e.update)),
+            Ok((1, SnapshotedUpdate::Incremental(Update0::Add(1))))
+        );
+        assert_eq!(
+            sub.recv().await.map(|e| (e.seq, e.update)),
+            Ok((1, SnapshotedUpdate::Snapshot(State0 { field: 43 })))
+        );
+    }
+
+    #[tokio::test]
+    async fn in_memory_subscribe_lagged() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0).with_feed_capacity(1);
+        let mut sub = db.subscribe();
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+        assert_eq!(sub.recv().await, Err(FeedErr::Lagged(1)));
+
+        assert_eq!(db.resync(&mut sub).field, 44);
+        db.update(Update0::Set(4)).await.expect("update");
+        assert_eq!(
+            sub.recv().await.map(|e| (e.seq, e.update)),
+            Ok((3, SnapshotedUpdate::Incremental(Update0::Set(4))))
+        );
+    }
+
+    /// Wait until the background task writes a snapshot
+    async fn wait_snapshot(backend: &InMemory<State0>) -> Vec<Envelope<State0>> {
+        for _ in 0..100 {
+            let upds = backend.updates().await.expect("collected");
+            if upds.first().is_some_and(|e| e.update.is_snapshot()) {
+                return upds;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Snapshot was not made");
+    }
+
+    #[tokio::test]
+    async fn in_memory_snapshot_every_updates() {
+        let state0 = State0 { field: 42 };
+        let db = Arc::new(AppendDb::new(InMemory::new(), state0));
+        let _task = db.start_snapshots(SnapshotPolicy::every_updates(2));
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let upds = wait_snapshot(&db.backend).await;
+        assert_eq!(
+            stripped(upds),
+            vec![(2, SnapshotedUpdate::Snapshot(State0 { field: 44 }))]
+        );
+        assert_eq!(db.updates_since_snapshot(), 0);
+        assert_eq!(db.replay_cost.read_atomic(), 0);
+    }
+
+    #[tokio::test]
+    async fn in_memory_snapshot_every_period() {
+        let state0 = State0 { field: 42 };
+        let db = Arc::new(AppendDb::new(InMemory::new(), state0));
+        let _task = db.start_snapshots(SnapshotPolicy::every(Duration::from_millis(10)));
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let upds = wait_snapshot(&db.backend).await;
+        assert_eq!(upds.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn in_memory_load_replay_cost() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0.clone());
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let db = AppendDb::new(db.backend, state0);
+        db.load().await.expect("load");
+        assert_eq!(db.updates_since_snapshot(), 2);
+        assert_eq!(db.replay_cost.read_atomic(), 2);
+    }
+
+    #[tokio::test]
+    async fn in_memory_state_at_seq() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0.clone());
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Set(4)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let at = |seq| db.state_at(HistoryPoint::Seq(seq));
+        assert_eq!(at(0).await.expect("state"), state0);
+        assert_eq!(at(1).await.expect("state").field, 43);
+        assert_eq!(at(2).await.expect("state").field, 44);
+        assert_eq!(at(3).await.expect("state").field, 4);
+        assert_eq!(at(4).await.expect("state").field, 5);
+        assert_eq!(db.get().field, 5);
+    }
+
+    #[tokio::test]
+    async fn in_memory_state_at_time() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0.clone());
+        let before = Utc::now();
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let middle = Utc::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        db.update(Update0::Set(4)).await.expect("update");
+
+        let at = |t| db.state_at(HistoryPoint::Time(t));
+        assert_eq!(at(before).await.expect("state"), state0);
+        assert_eq!(at(middle).await.expect("state").field, 43);
+        assert_eq!(at(Utc::now()).await.expect("state").field, 4);
+    }
+
+    #[tokio::test]
+    async fn in_memory_update_if() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0);
+        db.update_if(0, Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update_if(1, Update0::Add(1)).await.expect("update");
+
+        let res = db.update_if(1, Update0::Set(4)).await;
+        assert!(matches!(
+            res,
+            Err(AppendErr::VersionMismatch {
+                expected: 1,
+                actual: 2
+            })
+        ));
+        assert_eq!(db.get_versioned(), (State0 { field: 44 }, 2));
+    }
+
+    #[tokio::test]
+    async fn write_ahead_update_if() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::with_durability(InMemory::new(), state0, Durability::WriteAhead);
+        db.update_if(0, Update0::Add(1)).await.expect("update");
+
+        let res = db.update_if(0, Update0::Set(4)).await;
+        assert!(matches!(
+            res,
+            Err(AppendErr::VersionMismatch {
+                expected: 0,
+                actual: 1
+            })
+        ));
+        assert_eq!(db.get_versioned(), (State0 { field: 43 }, 1));
+    }
+
+    #[tokio::test]
+    async fn in_memory_update_with() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(InMemory::new(), state0);
+        db.update_with(|st| Ok(Update0::Set(st.field * 2)))
+            .await
+            .expect("update");
+        assert_eq!(db.get().field, 84);
+
+        let res = db.update_with(|st| Ok(Update0::Sub(st.field + 1))).await;
+        assert!(matches!(res, Err(AppendErr::Update(Underflow))));
+        assert_eq!(db.get_versioned(), (State0 { field: 84 }, 1));
+    }
+
+    #[tokio::test]
+    async fn in_memory_update_with_result() {
+        let state0 = State0 { field: 0 };
+        let db = AppendDb::new(InMemory::new(), state0);
+        let allocate = |st: &State0| Ok((Update0::Add(1), st.field + 1));
+
+        let (first, second) = tokio::join!(
+            db.update_with_result(allocate),
+            db.update_with_result(allocate)
+        );
+        let mut ids = vec![first.expect("update"), second.expect("update")];
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(db.get().field, 2);
+    }
+
+    #[tokio::test]
+    async fn write_ahead_update_with_result() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::with_durability(InMemory::new(), state0, Durability::WriteAhead);
+        let old = db
+            .update_with_result(|st| Ok((Update0::Set(4), st.field)))
+            .await
+            .expect("update");
+        assert_eq!(old, 42);
+        assert_eq!(db.get().field, 4);
+    }
+
+    #[tokio::test]
+    async fn write_ahead_updates() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::with_durability(InMemory::new(), state0, Durability::WriteAhead);
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Set(4)).await.expect("update");
+        assert_eq!(db.get().field, 4);
+
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 4);
+    }
+
+    #[tokio::test]
+    async fn write_ahead_failed_write() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::with_durability(Failing, state0.clone(), Durability::WriteAhead);
+        let res = db.update(Update0::Add(1)).await;
+        assert!(matches!(res, Err(AppendErr::Backend(_))));
+        assert_eq!(db.get(), state0);
+    }
+
+    #[tokio::test]
+    async fn memory_first_failed_write() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Failing, state0);
+        let res = db.update(Update0::Add(1)).await;
+        assert!(matches!(res, Err(AppendErr::Backend(_))));
+        assert_eq!(db.get().field, 43);
+    }
+
+    #[cfg(all(feature = "msgpack", feature = "cbor"))]
+    #[test]
+    fn binary_codecs() {
+        use super::codec::{Cbor, Codec, MessagePack};
+        fn roundtrip<C: Codec>() {
+            let state = State0 { field: 42 };
+            let encoded = C::encode(&state).expect("encode");
+            assert_eq!(C::decode::<State0>(&encoded).expect("decode"), state);
+            assert!(C::decode::<Vec<String>>(&encoded).is_err());
+        }
+        roundtrip::<MessagePack>();
+        roundtrip::<Cbor>();
+    }
+
+    #[cfg(feature = "file")]
+    mod file {
+        use super::*;
+        use crate::backend::file::{FileLog, Fsync};
+        use std::path::Path;
+        use tokio::fs::OpenOptions;
+        use tokio::io::AsyncWriteExt;
+
+        async fn open(path: &Path) -> AppendDb<FileLog<State0>> {
+            let backend = FileLog::open(path, Fsync::Always).await.expect("open");
+            let db = AppendDb::new(backend, State0 { field: 42 });
+            db.load().await.expect("load");
+            db
+        }
+
+        async fn append_raw(path: &Path, data: &[u8]) {
+            let mut file = OpenOptions::new()
+                .append(true)
+                .open(path)
+                .await
+                .expect("open");
+            file.write_all(data).await.expect("write");
+        }
+
+        #[tokio::test]
+        async fn file_reopen() {
+            let dir = tempfile::tempdir().expect("tempdir");
+            let path = dir.path().join("log");
+            let db = open(&path).await;
+            db.update(Update0::Add(1)).await.expect("update");
+            db.update_meta(Update0::Add(1), Metadata::default().with_actor("operator"))
+                .await
+                .expect("update");
+            drop(db);
+
+            let db = open(&path).await;
+            assert_eq!(db.get().field, 44);
+            assert_eq!(db.seq(), 2);
+            let upds = db.backend.updates().await.expect("collected");
+            assert_eq!(upds[1].meta.actor.as_deref(), Some("operator"));
+        }
+
+        #[tokio::test]
+        async fn file_snapshot_index() {
+            let dir = tempfile::tempdir().expect("tempdir");
+            let path = dir.path().join("log");
+            let db = open(&path).await;
+            db.update(Update0::Add(1)).await.expect("update");
+            db.snapshot().await.expect("snapshot");
+            db.update(Update0::Add(1)).await.expect("update");
+            drop(db);
+            assert!(dir.path().join("log.idx").exists());
+
+            let db = open(&path).await;
+            assert_eq!(db.get().field, 44);
+            assert_eq!(db.seq(), 2);
+            assert_eq!(
+                stripped(db.backend.updates().await.expect("collected")),
+                vec![
+                    (1, SnapshotedUpdate::Snapshot(State0 { field: 43 })),
+                    (2, SnapshotedUpdate::Incremental(Update0::Add(1))),
+                ]
+            );
+            assert_eq!(
+                db.state_at(HistoryPoint::Seq(0))
+                    .await
+                    .expect("state")
+                    .field,
+                42
+            );
+        }
+
+        #[tokio::test]
+        async fn file_torn_record() {
+            let dir = tempfile::tempdir().expect("tempdir");
+            let path = dir.path().join("log");
+            let db = open(&path).await;
+            db.update(Update0::Add(1)).await.expect("update");
+            drop(db);
+            append_raw(&path, b"{\"seq\":2,\"created\":").await;
+
+            let db = open(&path).await;
+            assert_eq!(db.get().field, 43);
+            assert_eq!(db.seq(), 1);
+            db.update(Update0::Add(1)).await.expect("update");
+            drop(db);
+
+            let db = open(&path).await;
+            assert_eq!(db.get().field, 44);
+        }
+
+        #[tokio::test]
+        async fn file_torn_batch() {
+            let dir = tempfile::tempdir().expect("tempdir");
+            let path = dir.path().join("log");
+            let db = open(&path).await;
+            db.update(Update0::Add(1)).await.expect("update");
+            db.update_many(vec![Update0::Add(1), Update0::Add(1)])
+                .await
+                .expect("update");
+            drop(db);
+
+            // Cut the last record of the batch
+            let data = tokio::fs::read(&path).await.expect("read");
+            let last = data[..data.len() - 1]
+                .iter()
+                .rposition(|c| *c == b'\n')
+                .expect("line");
+            tokio::fs::write(&path, &data[..last + 1])
+                .await
+                .expect("write");
+
+            let db = open(&path).await;
+            assert_eq!(db.get().field, 43);
+            assert_eq!(db.seq(), 1);
+        }
+
+        #[tokio::test]
+        async fn file_corrupted_record() {
+            let dir = tempfile::tempdir().expect("tempdir");
+            let path = dir.path().join("log");
+            let db = open(&path).await;
+            db.update(Update0::Add(1)).await.expect("update");
+            drop(db);
+            let valid = tokio::fs::read(&path).await.expect("read");
+            append_raw(&path, b"garbage\n").await;
+            append_raw(&path, &valid).await;
+            // Broken record in the middle of the log is not a crash leftover
+            let res = FileLog::<State0>::open(&path, Fsync::Always).await;
+            assert!(res.is_err());
+        }
+    }
+
+    #[cfg(feature = "redb")]
+    mod kv {
+        use super::*;
+        use crate::backend::kv::{self, Redb};
+
+        #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct State1 {
+            field: String,
+        }
+
+        impl State for State1 {
+            type Update = String;
+            type Err = Underflow;
+            const TABLE: &'static str = "updates2";
+
+            fn update(&mut self, upd: String) -> Result<(), Self::Err> {
+                self.field.push_str(&upd);
+                Ok(())
+            }
+        }
+
+        #[tokio::test]
+        async fn redb_reopen() {
+            let dir = tempfile::tempdir().expect("tempdir");
+            let path = dir.path().join("db.redb");
+            let db = AppendDb::new(Redb::open(&path).expect("open"), State0 { field: 42 });
+            db.update(Update0::Add(1)).await.expect("update");
+            db.update(Update0::Add(1)).await.expect("update");
+            drop(db);
+
+            let db = AppendDb::new(Redb::open(&path).expect("open"), State0 { field: 42 });
+            db.load().await.expect("load");
+            assert_eq!(db.get().field, 44);
+            assert_eq!(db.seq(), 2);
+        }
+
+        #[tokio::test]
+        async fn redb_updates_from_snapshot() {
+            let dir = tempfile::tempdir().expect("tempdir");
+            let backend = Redb::open(dir.path().join("db.redb")).expect("open");
+            let db = AppendDb::new(backend, State0 { field: 42 });
+            db.update(Update0::Add(1)).await.expect("update");
+            db.snapshot().await.expect("snapshot");
+            db.update(Update0::Set(4)).await.expect("update");
+
+            assert_eq!(
+                stripped(db.backend.updates().await.expect("collected")),
+                vec![
+                    (1, SnapshotedUpdate::Snapshot(State0 { field: 43 })),
+                    (2, SnapshotedUpdate::Incremental(Update0::Set(4))),
+                ]
+            );
+            let at = |seq| db.state_at(HistoryPoint::Seq(seq));
+            assert_eq!(at(0).await.expect("state").field, 42);
+            assert_eq!(at(2).await.expect("state").field, 4);
+        }
+
+        #[tokio::test]
+        async fn redb_duplicate_seq() {
+            let dir = tempfile::tempdir().expect("tempdir");
+            let path = dir.path().join("db.redb");
+            let db = AppendDb::new(Redb::open(&path).expect("open"), State0 { field: 42 });
+            db.update(Update0::Add(1)).await.expect("update");
+            drop(db);
+
+            // Writing before `load` starts numbering from the beginning
+            let db = AppendDb::new(Redb::open(&path).expect("open"), State0 { field: 42 });
+            let res = db.update(Update0::Add(100)).await;
+            assert!(matches!(
+                res,
+                Err(AppendErr::Backend(kv::Error::Duplicate(1)))
+            ));
+            let batch = vec![
+                Envelope::new(2, SnapshotedUpdate::Incremental(Update0::Add(1))),
+                Envelope::new(1, SnapshotedUpdate::Incremental(Update0::Add(100))),
+            ];
+            assert!(db.backend.write_batch(batch).await.is_err());
+
+            let db = AppendDb::new(db.backend, State0 { field: 42 });
+            db.load().await.expect("load");
+            assert_eq!(db.get().field, 43);
+            assert_eq!(db.seq(), 1);
+        }
+
+        #[tokio::test]
+        async fn redb_two_tables() {
+            let dir = tempfile::tempdir().expect("tempdir");
+            let backend = Redb::open(dir.path().join("db.redb")).expect("open");
+            let db0 = AppendDb::new(backend.clone(), State0 { field: 42 });
+            let db1 = AppendDb::new(
+                backend.duplicate(),
+                State1 {
+                    field: String::new(),
+                },
+            );
+            db0.update(Update0::Add(1)).await.expect("update");
+            db1.update("Hello".to_string()).await.expect("update");
+            db1.snapshot().await.expect("snapshot");
+            db1.update(" world!".to_string()).await.expect("update");
+
+            let db0 = AppendDb::new(db0.backend, State0 { field: 42 });
+            db0.load().await.expect("load");
+            assert_eq!(db0.get().field, 43);
+            db1.load().await.expect("load");
+            assert_eq!(db1.get().field, "Hello world!");
+        }
+
+        #[tokio::test]
+        async fn redb_compact() {
+            let dir = tempfile::tempdir().expect("tempdir");
+            let backend = Redb::open(dir.path().join("db.redb")).expect("open");
+            let state0 = State0 { field: 42 };
+            let db = AppendDb::new(backend, state0.clone());
+            db.update(Update0::Add(1)).await.expect("update");
+            db.snapshot().await.expect("snapshot");
+            db.update(Update0::Add(1)).await.expect("update");
+            db.snapshot().await.expect("snapshot");
+            db.update(Update0::Add(1)).await.expect("update");
+
+            let keep = |n| NonZeroUsize::new(n).expect("non zero");
+            assert_eq!(db.compact(keep(3)).await.expect("compact"), 0);
+            assert_eq!(db.compact(keep(2)).await.expect("compact"), 1);
+            assert_eq!(db.compact(keep(1)).await.expect("compact"), 2);
+            let res = db.state_at(HistoryPoint::Seq(1)).await;
+            assert!(matches!(res, Err(AppendErr::Compacted)));
+
+            let db = AppendDb::new(db.backend, state0);
+            db.load().await.expect("load");
+            assert_eq!(db.get().field, 45);
+            assert_eq!(db.seq(), 3);
+        }
     }
 }
@@ -0,0 +1,121 @@
+use crate::backend::class::{State, StateBackend};
+use crate::db::AppendDb;
+use std::future::pending;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+
+/// Defines when `AppendDb` makes snapshots automatically. A snapshot is made
+/// as soon as any of the set conditions is met.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotPolicy {
+    /// Make snapshot after given amount of updates since the last snapshot
+    pub every_updates: Option<u64>,
+    /// Make snapshot periodically if there were updates since the last snapshot
+    pub every: Option<Duration>,
+    /// Make snapshot when total `State::replay_cost` of updates since the last
+    /// snapshot reaches the threshold
+    pub max_replay_cost: Option<u64>,
+}
+
+impl SnapshotPolicy {
+    /// Snapshot after given amount of updates
+    pub fn every_updates(n: u64) -> Self {
+        SnapshotPolicy {
+            every_updates: Some(n),
+            ..Default::default()
+        }
+    }
+
+    /// Snapshot periodically
+    pub fn every(period: Duration) -> Self {
+        SnapshotPolicy {
+            every: Some(period),
+            ..Default::default()
+        }
+    }
+
+    /// Snapshot when replay cost reaches the threshold
+    pub fn max_replay_cost(cost: u64) -> Self {
+        SnapshotPolicy {
+            max_replay_cost: Some(cost),
+            ..Default::default()
+        }
+    }
+
+    fn is_due(&self, updates: u64, cost: u64, elapsed: Duration) -> bool {
+        updates > 0
+            && (self.every_updates.is_some_and(|n| updates >= n)
+                || self.max_replay_cost.is_some_and(|c| cost >= c)
+                || self.every.is_some_and(|t| elapsed >= t))
+    }
+}
+
+/// Handle of the background task started by `AppendDb::start_snapshots`.
+/// Dropping the handle stops the task.
+pub struct SnapshotTask {
+    handle: JoinHandle<()>,
+}
+
+impl SnapshotTask {
+    pub(crate) fn new(handle: JoinHandle<()>) -> Self {
+        SnapshotTask { handle }
+    }
+
+    /// Stop making snapshots
+    pub fn stop(self) {}
+}
+
+impl Drop for SnapshotTask {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Body of the snapshotting task. Holds only weak reference to the database, so the
+/// task ends when the database is dropped.
+pub(crate) async fn run<St, Backend>(
+    db: Weak<AppendDb<Backend>>,
+    mut updated: watch::Receiver<u64>,
+    policy: SnapshotPolicy,
+) where
+    St: Clone + State + Sync + Send + 'static,
+    Backend: StateBackend<State = St>,
+{
+    let mut ticker = policy.every.map(|period| {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    });
+    let mut last_snapshot = Instant::now();
+    loop {
+        let tick = async {
+            match ticker.as_mut() {
+                Some(ticker) => {
+                    ticker.tick().await;
+                }
+                None => pending().await,
+            }
+        };
+        tokio::select! {
+            res = updated.changed() => if res.is_err() {
+                break;
+            },
+            _ = tick => {},
+        }
+        let db = match db.upgrade() {
+            Some(db) => db,
+            None => break,
+        };
+        let updates = db.updates_since_snapshot();
+        let cost = db.replay_cost.read_atomic();
+        if policy.is_due(updates, cost, last_snapshot.elapsed()) {
+            match db.snapshot().await {
+                Ok(_) => last_snapshot = Instant::now(),
+                Err(e) => log::error!("Failed to make automatic snapshot: {}", e),
+            }
+        }
+    }
+}
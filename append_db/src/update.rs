@@ -0,0 +1,117 @@
+use crate::backend::class::{SnapshotedUpdate, State};
+use crate::codec::{Codec, CodecError};
+use std::borrow::Cow;
+use std::fmt;
+use thiserror::Error;
+
+/// Update tags are simple strings.
+pub type UpdateTag = Cow<'static, str>;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct UnknownUpdateTag(pub String);
+
+impl std::error::Error for UnknownUpdateTag {}
+
+impl fmt::Display for UnknownUpdateTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Given UpdateTag '{}' is unknown", self.0)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum UpdateBodyError {
+    #[error("Unknown update tag: {0}")]
+    UnknownTag(#[from] UnknownUpdateTag),
+    #[error("Failed to deserialize update with version {0} and tag {1}: {2}. Body: {3}")]
+    Deserialize(u16, UpdateTag, CodecError, String),
+    #[error("Failed to serialize update with tag {0}: {1}")]
+    Serialize(UpdateTag, CodecError),
+    #[error("Unknown version tag: {0}")]
+    UnexpectedVersion(u16),
+}
+
+pub trait HasUpdateTag {
+    /// Deserialize given tagged value encoded by the codec
+    /// according to version number.
+    fn deserialize_by_tag<C: Codec>(
+        tag: &UpdateTag,
+        version: u16,
+        value: &C::Encoded,
+    ) -> Result<Self, UpdateBodyError>
+    where
+        Self: std::marker::Sized;
+
+    /// Get tag of the value. Don't use 'snapshot' tag
+    /// as it is internal for snapshots updates.
+    fn get_tag(&self) -> UpdateTag;
+
+    /// Get current version of the value
+    fn get_version(&self) -> u16;
+
+    /// Serialize internal value without tag. If 'Self'
+    /// is enum with variants A(AValue) and B(BValue),
+    /// the serialized values should be AValue and BValue
+    /// without wrappers.
+    fn serialize_untagged<C: Codec>(&self) -> Result<C::Encoded, UpdateBodyError>;
+}
+
+/// Helps to deserialized snapshoted states
+pub trait VersionedState {
+    /// Deserialize snapshoted state with given version tag.
+    fn deserialize_with_version<C: Codec>(
+        version: u16,
+        value: &C::Encoded,
+    ) -> Result<Self, UpdateBodyError>
+    where
+        Self: std::marker::Sized;
+
+    /// Get current version of the state
+    fn get_version(&self) -> u16;
+
+    /// Serialize current state by the codec with the current version in mind
+    fn serialize<C: Codec>(&self) -> Result<C::Encoded, UpdateBodyError>;
+}
+
+pub const SNAPSHOT_TAG: &str = "snapshot";
+
+impl<Upd: HasUpdateTag, St: State<Update = Upd> + VersionedState> HasUpdateTag
+    for SnapshotedUpdate<St>
+{
+    fn deserialize_by_tag<C: Codec>(
+        tag: &UpdateTag,
+        version: u16,
+        value: &C::Encoded,
+    ) -> Result<Self, UpdateBodyError>
+    where
+        Self: std::marker::Sized,
+    {
+        if tag == SNAPSHOT_TAG {
+            let st = St::deserialize_with_version::<C>(version, value)?;
+            Ok(SnapshotedUpdate::Snapshot(st))
+        } else {
+            let res = Upd::deserialize_by_tag::<C>(tag, version, value)?;
+            Ok(SnapshotedUpdate::Incremental(res))
+        }
+    }
+
+    fn get_tag(&self) -> UpdateTag {
+        match self {
+            SnapshotedUpdate::Snapshot(_) => Cow::Borrowed(SNAPSHOT_TAG),
+            SnapshotedUpdate::Incremental(v) => v.get_tag(),
+        }
+    }
+
+    fn get_version(&self) -> u16 {
+        match self {
+            SnapshotedUpdate::Snapshot(v) => v.get_version(),
+            SnapshotedUpdate::Incremental(v) => v.get_version(),
+        }
+    }
+
+    fn serialize_untagged<C: Codec>(&self) -> Result<C::Encoded, UpdateBodyError> {
+        match self {
+            SnapshotedUpdate::Snapshot(v) => v.serialize::<C>(),
+            SnapshotedUpdate::Incremental(v) => v.serialize_untagged::<C>(),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Lives in a separate test binary as the file size limit applies to the whole process
+#![cfg(all(unix, feature = "file"))]
+
+use append_db::backend::class::{Envelope, SnapshotedUpdate, State, StateBackend};
+use append_db::backend::file::{FileLog, Fsync};
+use std::convert::Infallible;
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Counter(u64);
+
+impl State for Counter {
+    type Update = u64;
+    type Err = Infallible;
+
+    fn update(&mut self, upd: u64) -> Result<(), Self::Err> {
+        self.0 += upd;
+        Ok(())
+    }
+}
+
+fn limit_file_size(limit: libc::rlim_t) {
+    let rlimit = libc::rlimit {
+        rlim_cur: limit,
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    // SAFETY: plain libc calls with valid arguments
+    unsafe {
+        // Writes beyond the limit fail with `EFBIG` instead of killing the process
+        libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+        assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &rlimit), 0);
+    }
+}
+
+#[tokio::test]
+async fn file_partial_write() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let path = dir.path().join("log");
+    let log = FileLog::<Counter>::open(&path, Fsync::Never)
+        .await
+        .expect("open");
+    let upd = |seq| Envelope::new(seq, SnapshotedUpdate::Incremental(seq));
+    log.write(upd(1)).await.expect("write");
+    let len = tokio::fs::metadata(&path).await.expect("metadata").len();
+
+    // Only a part of the record fits
+    limit_file_size(len + 10);
+    assert!(log.write(upd(2)).await.is_err());
+    limit_file_size(libc::RLIM_INFINITY);
+    let after = tokio::fs::metadata(&path).await.expect("metadata").len();
+    assert_eq!(after, len);
+
+    log.write(upd(3)).await.expect("write");
+    drop(log);
+    let log = FileLog::<Counter>::open(&path, Fsync::Never)
+        .await
+        .expect("open");
+    let seqs: Vec<_> = log
+        .updates()
+        .await
+        .expect("updates")
+        .iter()
+        .map(|e| e.seq)
+        .collect();
+    assert_eq!(seqs, vec![1, 3]);
+}
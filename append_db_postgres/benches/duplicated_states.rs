@@ -0,0 +1,146 @@
+//! Throughput of several states that share one pool with `Postgres::duplicate` and
+//! write at the same time. Run with `DATABASE_URL` pointing to a server where the
+//! benchmark can create a temporary database: `cargo bench -p append_db_postgres`.
+
+use append_db::backend::class::State;
+use append_db::db::AppendDb;
+use append_db_postgres::backend::{Pool, Postgres};
+use append_db_postgres::update::{HasUpdateTag, VersionedState};
+use append_db_postgres_derive::{HasUpdateTag, VersionedState};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Connection, Executor, PgConnection};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Instant;
+
+const WRITERS_PER_STATE: usize = 4;
+const UPDATES_PER_WRITER: usize = 250;
+
+macro_rules! counter_state {
+    ($state:ident, $update:ident, $table:literal) => {
+        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, VersionedState)]
+        struct $state {
+            value: u64,
+        }
+
+        #[derive(Clone, Debug, PartialEq, HasUpdateTag)]
+        enum $update {
+            Add(u64),
+        }
+
+        impl State for $state {
+            type Update = $update;
+            type Err = Infallible;
+
+            const TABLE: &'static str = $table;
+
+            fn update(&mut self, upd: $update) -> Result<(), Infallible> {
+                match upd {
+                    $update::Add(v) => self.value += v,
+                }
+                Ok(())
+            }
+        }
+    };
+}
+
+counter_state!(Counter0, Update0, "bench_0");
+counter_state!(Counter1, Update1, "bench_1");
+counter_state!(Counter2, Update2, "bench_2");
+counter_state!(Counter3, Update3, "bench_3");
+
+/// Write updates to the state from several tasks at once
+async fn hammer<St>(db: Arc<AppendDb<Postgres<St>>>, upd: St::Update)
+where
+    St: State + VersionedState + Clone + Send + Sync + 'static,
+    St::Update: HasUpdateTag + Send + Sync,
+{
+    let writers = (0..WRITERS_PER_STATE).map(|_| {
+        let db = db.clone();
+        let upd = upd.clone();
+        tokio::spawn(async move {
+            for _ in 0..UPDATES_PER_WRITER {
+                db.update(upd.clone()).await.expect("update");
+            }
+        })
+    });
+    for writer in join_all(writers).await {
+        writer.expect("writer");
+    }
+}
+
+/// Run writers of the first `states` states concurrently and print the throughput
+async fn measure(pool: &Pool, states: usize) {
+    let postgres = Postgres::<Counter0>::new(pool.clone());
+    let mut runs = vec![];
+    let db0 = AppendDb::new(postgres.clone(), Counter0 { value: 0 });
+    runs.push(tokio::spawn(hammer(Arc::new(db0), Update0::Add(1))));
+    if states > 1 {
+        let db1 = AppendDb::new(postgres.duplicate(), Counter1 { value: 0 });
+        runs.push(tokio::spawn(hammer(Arc::new(db1), Update1::Add(1))));
+    }
+    if states > 2 {
+        let db2 = AppendDb::new(postgres.duplicate(), Counter2 { value: 0 });
+        runs.push(tokio::spawn(hammer(Arc::new(db2), Update2::Add(1))));
+    }
+    if states > 3 {
+        let db3 = AppendDb::new(postgres.duplicate(), Counter3 { value: 0 });
+        runs.push(tokio::spawn(hammer(Arc::new(db3), Update3::Add(1))));
+    }
+
+    let start = Instant::now();
+    for run in join_all(runs).await {
+        run.expect("state");
+    }
+    let elapsed = start.elapsed();
+    let updates = states * WRITERS_PER_STATE * UPDATES_PER_WRITER;
+    println!(
+        "{} state(s), {} updates in {:?}: {:.0} updates/s",
+        states,
+        updates,
+        elapsed,
+        updates as f64 / elapsed.as_secs_f64()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is required for the benchmark");
+    let database = format!("append_db_bench_{}", std::process::id());
+    let mut admin = PgConnection::connect(&url).await.expect("connect");
+    admin
+        .execute(format!("create database {}", database).as_str())
+        .await
+        .expect("create database");
+
+    let options = url
+        .parse::<sqlx::postgres::PgConnectOptions>()
+        .expect("database url")
+        .database(&database);
+    let pool = PgPoolOptions::new()
+        .max_connections(32)
+        .connect_with(options)
+        .await
+        .expect("connect");
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await
+        .expect("migrate");
+    for table in ["bench_0", "bench_1", "bench_2", "bench_3"] {
+        pool.execute(format!("create table {} (like updates including all)", table).as_str())
+            .await
+            .expect("create table");
+    }
+
+    for states in [1, 2, 4] {
+        measure(&pool, states).await;
+    }
+
+    pool.close().await;
+    admin
+        .execute(format!("drop database {}", database).as_str())
+        .await
+        .expect("drop database");
+}
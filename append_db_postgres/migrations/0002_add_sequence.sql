@@ -0,0 +1,13 @@
+alter table updates add column seq bigint;
+update updates set seq = id;
+alter table updates alter column seq set not null;
+-- Incremental updates are unique by sequence number, a snapshot shares it
+create unique index updates_seq_idx on updates(seq) where tag <> 'snapshot';
+create index updates_snapshot_seq_idx on updates(seq) where tag = 'snapshot';
+
+alter table updates2 add column seq bigint;
+update updates2 set seq = id;
+alter table updates2 alter column seq set not null;
+-- Incremental updates are unique by sequence number, a snapshot shares it
+create unique index updates2_seq_idx on updates2(seq) where tag <> 'snapshot';
+create index updates2_snapshot_seq_idx on updates2(seq) where tag = 'snapshot';
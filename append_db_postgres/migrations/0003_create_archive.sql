@@ -0,0 +1,17 @@
+create table updates_archive(
+    id integer primary key,
+    created timestamp not null,
+    seq bigint not null,
+    version smallint not null,
+    tag text not null,
+    body jsonb not null
+);
+
+create table updates2_archive(
+    id integer primary key,
+    created timestamp not null,
+    seq bigint not null,
+    version smallint not null,
+    tag text not null,
+    body jsonb not null
+);
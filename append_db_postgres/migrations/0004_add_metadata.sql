@@ -0,0 +1,19 @@
+alter table updates add column actor text;
+alter table updates add column correlation_id text;
+alter table updates add column causation_id text;
+alter table updates add column headers jsonb not null default '{}';
+
+alter table updates2 add column actor text;
+alter table updates2 add column correlation_id text;
+alter table updates2 add column causation_id text;
+alter table updates2 add column headers jsonb not null default '{}';
+
+alter table updates_archive add column actor text;
+alter table updates_archive add column correlation_id text;
+alter table updates_archive add column causation_id text;
+alter table updates_archive add column headers jsonb not null default '{}';
+
+alter table updates2_archive add column actor text;
+alter table updates2_archive add column correlation_id text;
+alter table updates2_archive add column causation_id text;
+alter table updates2_archive add column headers jsonb not null default '{}';
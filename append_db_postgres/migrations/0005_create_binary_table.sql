@@ -0,0 +1,14 @@
+create table updates_bin(
+    id serial primary key,
+    created timestamp not null,
+    seq bigint not null,
+    version smallint not null,
+    tag text not null,
+    body bytea not null,
+    actor text,
+    correlation_id text,
+    causation_id text,
+    headers jsonb not null default '{}'
+);
+create unique index updates_bin_seq_idx on updates_bin(seq) where tag <> 'snapshot';
+create index updates_bin_snapshot_seq_idx on updates_bin(seq) where tag = 'snapshot';
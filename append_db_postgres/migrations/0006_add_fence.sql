@@ -0,0 +1,11 @@
+create table append_db_leases(
+    tbl text primary key,
+    token bigint not null
+);
+
+alter table updates add column fence bigint;
+alter table updates2 add column fence bigint;
+alter table updates_bin add column fence bigint;
+
+alter table updates_archive add column fence bigint;
+alter table updates2_archive add column fence bigint;
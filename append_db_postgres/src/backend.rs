@@ -1,12 +1,18 @@
-use crate::update::{HasUpdateTag, UpdateBodyError, VersionedState};
-pub use append_db::backend::class::{SnapshotedUpdate, State, StateBackend};
+use crate::update::{HasUpdateTag, UpdateBodyError, VersionedState, SNAPSHOT_TAG};
+pub use append_db::backend::class::{
+    Envelope, HistoryPoint, Metadata, SnapshotedUpdate, State, StateBackend,
+};
+pub use append_db::codec::{Codec, Json};
+use async_stream::try_stream;
 use async_trait::async_trait;
 use chrono::prelude::*;
-use futures::StreamExt;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use sqlx::postgres::{PgConnection, PgRow};
 use sqlx::Row;
 use std::borrow::Cow;
 use std::marker::PhantomData;
-use std::ops::Deref;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::Mutex;
@@ -22,27 +28,182 @@ pub enum Error {
     UpdateBody(#[from] UpdateBodyError),
     #[error("Failed to decode/encode JSON: {0}")]
     Encoding(#[from] serde_json::Error),
+    #[error("Writer lease of table {0} is held by another process")]
+    LeaseBusy(&'static str),
+    #[error("Writer lease of table {table} is taken over, fencing token {fence} is outdated")]
+    LeaseLost { table: &'static str, fence: i64 },
+    #[error("Update with the same sequence number is already stored in {0}")]
+    Duplicate(&'static str),
 }
 
+/// Exclusive right to write to the table of the state, see `Postgres::lease`
+pub struct Lease {
+    /// Fencing token that is stored in the `fence` column of every written row
+    pub fence: i64,
+    /// Holds the advisory lock until the lease is dropped
+    _conn: Mutex<PgConnection>,
+}
+
+/// Storage in Postgres table `State::TABLE`. Bodies are encoded by the codec `C`,
+/// the `body` column is `jsonb` for `Json` and `bytea` for binary codecs.
 #[derive(Clone)]
-pub struct Postgres<St: State> {
-    pub pool: Arc<Mutex<Pool>>,
+pub struct Postgres<St: State, C: Codec = Json> {
+    /// The pool is shared by clones and duplicates, writes of different states and
+    /// readers proceed concurrently. Updates are ordered by their sequence numbers.
+    pub pool: Pool,
+    /// Move compacted rows into `<TABLE>_archive` table instead of deleting them
+    pub archive: bool,
+    /// Send notification to `Postgres::channel` on each write for followers
+    pub notify: bool,
+    /// Writer lease that fences writes of previous holders
+    pub lease: Option<Arc<Lease>>,
     pub state_proxy: PhantomData<St>,
+    pub codec_proxy: PhantomData<C>,
 }
 
 impl<St: State> Postgres<St> {
     pub fn new(pool: Pool) -> Self {
         Postgres {
-            pool: Arc::new(Mutex::new(pool)),
+            pool,
+            archive: false,
+            notify: false,
+            lease: None,
             state_proxy: PhantomData,
+            codec_proxy: PhantomData,
+        }
+    }
+}
+
+impl<St: State, C: Codec> Postgres<St, C> {
+    /// Encode bodies with another codec. The table must have `body` column of the
+    /// matching type.
+    pub fn with_codec<C2: Codec>(self) -> Postgres<St, C2> {
+        Postgres {
+            pool: self.pool,
+            archive: self.archive,
+            notify: self.notify,
+            lease: self.lease,
+            state_proxy: PhantomData,
+            codec_proxy: PhantomData,
+        }
+    }
+
+    /// Keep compacted rows in `<TABLE>_archive` table with the same layout as the
+    /// table of the state.
+    pub fn with_archive(mut self) -> Self {
+        self.archive = true;
+        self
+    }
+
+    /// Notify followers about each write (see `follower::follow`)
+    pub fn with_notify(mut self) -> Self {
+        self.notify = true;
+        self
+    }
+
+    /// Channel of notifications about new rows in the table of the state
+    pub fn channel() -> String {
+        format!("append_db_{}", St::TABLE)
+    }
+
+    /// Send notification about the written update, it is delivered when the
+    /// transaction commits
+    async fn notify<'c, E>(&self, executor: E, seq: u64) -> Result<(), Error>
+    where
+        E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+    {
+        if self.notify {
+            sqlx::query("select pg_notify($1, $2)")
+                .bind(Self::channel())
+                .bind(seq.to_string())
+                .execute(executor)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Become the only writer of the table of the state, waiting until the current
+    /// holder of the lease releases it. The lease is a session advisory lock held by a
+    /// dedicated connection, so it is released when the backend is dropped or the
+    /// connection is lost.
+    ///
+    /// Each lease gets a greater fencing token than the previous one. Writes check that
+    /// their token is still the latest and fail with `Error::LeaseLost` otherwise.
+    /// Rows keep the token in the `fence` column (see `migrations/0006_add_fence.sql`),
+    /// which is required only for the tables and archives of the leased states.
+    pub async fn lease(self) -> Result<Self, Error> {
+        self.acquire_lease("select pg_advisory_lock(hashtext($1)), true as acquired")
+            .await
+    }
+
+    /// Same as `lease`, but fails with `Error::LeaseBusy` if the lease is held by
+    /// another process
+    pub async fn try_lease(self) -> Result<Self, Error> {
+        self.acquire_lease("select pg_try_advisory_lock(hashtext($1)) as acquired")
+            .await
+    }
+
+    async fn acquire_lease(mut self, lock_query: &str) -> Result<Self, Error> {
+        let mut conn = self.pool.acquire().await?.detach();
+        let acquired: bool = sqlx::query(lock_query)
+            .bind(Self::channel())
+            .fetch_one(&mut conn)
+            .await?
+            .try_get("acquired")?;
+        if !acquired {
+            return Err(Error::LeaseBusy(St::TABLE));
+        }
+        let fence: i64 = sqlx::query(
+            "insert into append_db_leases (tbl, token) values ($1, 1) \
+             on conflict (tbl) do update set token = append_db_leases.token + 1 returning token",
+        )
+        .bind(St::TABLE)
+        .fetch_one(&mut conn)
+        .await?
+        .try_get("token")?;
+        self.lease = Some(Arc::new(Lease {
+            fence,
+            _conn: Mutex::new(conn),
+        }));
+        Ok(self)
+    }
+
+    /// Fencing token of the lease, if it is checked in the transaction. The latest token
+    /// is locked until the transaction ends, so the lease can't be taken over in between.
+    async fn fence(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> Result<Option<i64>, Error> {
+        let lease = match &self.lease {
+            Some(lease) => lease,
+            None => return Ok(None),
+        };
+        let latest: i64 =
+            sqlx::query("select token from append_db_leases where tbl = $1 for share")
+                .bind(St::TABLE)
+                .fetch_one(&mut *tx)
+                .await?
+                .try_get("token")?;
+        if latest == lease.fence {
+            Ok(Some(lease.fence))
+        } else {
+            Err(Error::LeaseLost {
+                table: St::TABLE,
+                fence: lease.fence,
+            })
         }
     }
 
     /// Duplicates a connection to the same pool, casting St to St2
-    pub fn duplicate<St2: State>(&self) -> Postgres<St2> {
+    pub fn duplicate<St2: State>(&self) -> Postgres<St2, C> {
         Postgres {
             pool: self.pool.clone(),
+            archive: self.archive,
+            notify: self.notify,
+            // The lease is bound to the table of the state
+            lease: None,
             state_proxy: PhantomData,
+            codec_proxy: PhantomData,
         }
     }
 }
@@ -51,61 +212,289 @@ impl<St: State> Postgres<St> {
 impl<
         Upd: HasUpdateTag + Send,
         St: State<Update = Upd> + VersionedState + Clone + Send + Sync + 'static,
-    > StateBackend for Postgres<St>
+        C: Codec,
+    > StateBackend for Postgres<St, C>
+where
+    C::Encoded: Body,
 {
     type State = St;
     type Err = Error;
 
-    async fn write(&self, update: SnapshotedUpdate<St>) -> Result<(), Self::Err> {
-        let now = Utc::now().naive_utc();
-        let tag = format!("{}", update.get_tag());
-        let body = update.serialize_untagged()?;
-        let pool = self.pool.lock().await;
-        let query = format!(
-            "insert into {} (created, version, tag, body) values ($1, $2, $3, $4)",
-            St::TABLE
-        );
-        let query = sqlx::query(&query)
-            .bind(now)
-            .bind(update.get_version() as i16)
-            .bind(tag)
-            .bind(body)
-            .execute(pool.deref());
-        query.await?;
+    async fn write(&self, envelope: Envelope<St>) -> Result<(), Self::Err> {
+        if self.lease.is_some() {
+            return self.write_batch(vec![envelope]).await;
+        }
+        let seq = envelope.seq;
+        insert::<St, C, _>(&self.pool, vec![envelope], None).await?;
+        self.notify(&self.pool, seq).await
+    }
+
+    async fn write_batch(&self, envelopes: Vec<Envelope<St>>) -> Result<(), Self::Err> {
+        let mut tx = self.pool.begin().await?;
+        let fence = self.fence(&mut tx).await?;
+        let last_seq = envelopes.iter().map(|e| e.seq).max();
+        let mut rest = envelopes;
+        while !rest.is_empty() {
+            let tail = rest.split_off(rest.len().min(INSERT_ROWS));
+            insert::<St, C, _>(&mut tx, rest, fence).await?;
+            rest = tail;
+        }
+        if let Some(seq) = last_seq {
+            self.notify(&mut tx, seq).await?;
+        }
+        tx.commit().await?;
         Ok(())
     }
 
-    async fn updates(&self) -> Result<Vec<SnapshotedUpdate<St>>, Self::Err> {
-        let pool = self.pool.lock().await;
-        let mut conn = pool.acquire().await?;
-        let query = format!("select * from {} order by created desc", St::TABLE);
-        let res = sqlx::query(&query).fetch(&mut conn).fuse();
-        futures::pin_mut!(res);
-        let mut parsed: Vec<SnapshotedUpdate<St>> = vec![];
-        loop {
-            let item = futures::select! {
-                mmrow = res.next() => {
-                    if let Some(mrow) = mmrow {
-                        let r = mrow?;
-                        let body = <SnapshotedUpdate<St>>::deserialize_by_tag(
-                            &Cow::Owned(r.try_get("tag")?),
-                            r.try_get::<i16, &str>("version")? as u16,
-                            r.try_get("body")?
-                        )?;
-                        body
-                    } else {
-                        break;
-                    }
-                },
-                complete => break,
+    async fn updates(&self) -> Result<Vec<Envelope<St>>, Self::Err> {
+        self.updates_stream().try_collect().await
+    }
+
+    fn updates_stream(&self) -> BoxStream<'_, Result<Envelope<St>, Self::Err>> {
+        Box::pin(try_stream! {
+            let query = format!(
+                "select id, seq from {} where tag = '{}' order by seq desc, id desc limit 1",
+                St::TABLE,
+                SNAPSHOT_TAG
+            );
+            let snapshot = sqlx::query(&query).fetch_optional(&self.pool).await?;
+            let (snapshot_id, snapshot_seq): (i32, i64) = match snapshot {
+                Some(r) => (r.try_get("id")?, r.try_get("seq")?),
+                None => (-1, 0),
             };
-            let is_end = item.is_snapshot();
-            parsed.push(item);
-            if is_end {
-                break;
+
+            // The snapshot goes first as all other rows have greater sequence numbers
+            let query = format!(
+                "select * from {} where id = $1 or (tag <> '{}' and seq > $2) order by seq, id",
+                St::TABLE,
+                SNAPSHOT_TAG
+            );
+            let mut rows = sqlx::query(&query)
+                .bind(snapshot_id)
+                .bind(snapshot_seq)
+                .fetch(&self.pool);
+            while let Some(r) = rows.try_next().await? {
+                yield decode_row::<St, C>(&r)?;
+            }
+        })
+    }
+
+    async fn compact(&self, keep_snapshots: NonZeroUsize) -> Result<u64, Self::Err> {
+        let query = format!(
+            "select id, seq from {} where tag = '{}' order by seq desc, id desc offset $1 limit 1",
+            St::TABLE,
+            SNAPSHOT_TAG
+        );
+        let snapshot = sqlx::query(&query)
+            .bind(keep_snapshots.get() as i64 - 1)
+            .fetch_optional(&self.pool)
+            .await?;
+        let (snapshot_id, snapshot_seq): (i32, i64) = match snapshot {
+            Some(r) => (r.try_get("id")?, r.try_get("seq")?),
+            None => return Ok(0),
+        };
+
+        // Everything that goes before the snapshot in `Envelope::order_key` order
+        let obsolete = format!(
+            "seq < $1 or (seq = $1 and (tag <> '{0}' or id < $2))",
+            SNAPSHOT_TAG
+        );
+        let query = if self.archive {
+            let mut columns =
+                "id, created, seq, version, tag, body, actor, correlation_id, causation_id, headers"
+                    .to_owned();
+            if self.lease.is_some() {
+                columns.push_str(", fence");
+            }
+            format!(
+                "with moved as (delete from {0} where {1} returning *) \
+                 insert into {0}_archive ({2}) select {2} from moved",
+                St::TABLE,
+                obsolete,
+                columns
+            )
+        } else {
+            format!("delete from {} where {}", St::TABLE, obsolete)
+        };
+        let res = sqlx::query(&query)
+            .bind(snapshot_seq)
+            .bind(snapshot_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn updates_until(
+        &self,
+        point: HistoryPoint,
+    ) -> Result<Option<Vec<Envelope<St>>>, Self::Err> {
+        let query = format!(
+            "select * from {} order by seq, tag = '{}', id limit 1",
+            St::TABLE,
+            SNAPSHOT_TAG
+        );
+        let oldest = sqlx::query(&query).fetch_optional(&self.pool).await?;
+        if let Some(r) = oldest {
+            if !point.is_retained(&decode_row::<St, C>(&r)?) {
+                return Ok(None);
             }
         }
-        parsed.reverse();
-        Ok(parsed)
+        let last_seq: i64 = match point {
+            HistoryPoint::Seq(seq) => seq as i64,
+            HistoryPoint::Time(t) => {
+                let query = format!(
+                    "select coalesce(max(seq), 0) as seq from {} where created <= $1",
+                    St::TABLE
+                );
+                sqlx::query(&query)
+                    .bind(t.naive_utc())
+                    .fetch_one(&self.pool)
+                    .await?
+                    .try_get("seq")?
+            }
+        };
+
+        let query = format!(
+            "select * from {} where tag = '{}' and seq <= $1 order by seq desc, id desc limit 1",
+            St::TABLE,
+            SNAPSHOT_TAG
+        );
+        let snapshot = sqlx::query(&query)
+            .bind(last_seq)
+            .fetch_optional(&self.pool)
+            .await?;
+        let mut parsed: Vec<Envelope<St>> = vec![];
+        let mut first_seq = 0;
+        if let Some(r) = snapshot {
+            let item = decode_row::<St, C>(&r)?;
+            first_seq = item.seq as i64;
+            parsed.push(item);
+        }
+
+        let query = format!(
+            "select * from {} where tag <> '{}' and seq > $1 and seq <= $2 order by seq, id",
+            St::TABLE,
+            SNAPSHOT_TAG
+        );
+        let rows = sqlx::query(&query)
+            .bind(first_seq)
+            .bind(last_seq)
+            .fetch_all(&self.pool)
+            .await?;
+        for r in rows {
+            parsed.push(decode_row::<St, C>(&r)?);
+        }
+        Ok(Some(parsed))
     }
 }
+
+/// Encoded body that can be stored in the `body` column
+pub trait Body:
+    for<'q> sqlx::Encode<'q, sqlx::Postgres>
+    + for<'r> sqlx::Decode<'r, sqlx::Postgres>
+    + sqlx::Type<sqlx::Postgres>
+    + Send
+    + Sync
+{
+}
+
+impl<T> Body for T where
+    T: for<'q> sqlx::Encode<'q, sqlx::Postgres>
+        + for<'r> sqlx::Decode<'r, sqlx::Postgres>
+        + sqlx::Type<sqlx::Postgres>
+        + Send
+        + Sync
+{
+}
+
+/// Decode single row of the table of the state
+pub(crate) fn decode_row<St, C>(r: &PgRow) -> Result<Envelope<St>, Error>
+where
+    St: State + VersionedState,
+    St::Update: HasUpdateTag,
+    C: Codec,
+    C::Encoded: Body,
+{
+    let body = <SnapshotedUpdate<St>>::deserialize_by_tag::<C>(
+        &Cow::Owned(r.try_get("tag")?),
+        r.try_get::<i16, &str>("version")? as u16,
+        &r.try_get::<C::Encoded, &str>("body")?,
+    )?;
+    let created = DateTime::from_utc(r.try_get::<NaiveDateTime, &str>("created")?, Utc);
+    let meta = Metadata {
+        actor: r.try_get("actor")?,
+        correlation_id: r.try_get("correlation_id")?,
+        causation_id: r.try_get("causation_id")?,
+        headers: serde_json::from_value(r.try_get("headers")?)?,
+    };
+    Ok(
+        Envelope::with_created(r.try_get::<i64, &str>("seq")? as u64, created, body)
+            .with_meta(meta),
+    )
+}
+
+/// Rows of a single insert statement, each of them takes up to 10 of 65535 parameters
+const INSERT_ROWS: usize = 1000;
+
+/// Insert updates into the table of the state with a single statement. The `fence`
+/// column is written only with a fencing token.
+async fn insert<'c, St, C, E>(
+    executor: E,
+    envelopes: Vec<Envelope<St>>,
+    fence: Option<i64>,
+) -> Result<(), Error>
+where
+    St: State + VersionedState,
+    St::Update: HasUpdateTag,
+    C: Codec,
+    C::Encoded: Body,
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let (width, fence_column) = match fence {
+        Some(_) => (10, ", fence"),
+        None => (9, ""),
+    };
+    let rows: Vec<String> = (0..envelopes.len())
+        .map(|i| {
+            let params: Vec<String> = (1..=width).map(|j| format!("${}", i * width + j)).collect();
+            format!("({})", params.join(", "))
+        })
+        .collect();
+    let query = format!(
+        "insert into {} (created, seq, version, tag, body, actor, correlation_id, causation_id, headers{}) \
+         values {}",
+        St::TABLE,
+        fence_column,
+        rows.join(", ")
+    );
+    let mut query = sqlx::query(&query);
+    for envelope in envelopes {
+        let update = envelope.update;
+        let meta = envelope.meta;
+        query = query
+            .bind(envelope.created.naive_utc())
+            .bind(envelope.seq as i64)
+            .bind(update.get_version() as i16)
+            .bind(format!("{}", update.get_tag()))
+            .bind(update.serialize_untagged::<C>()?)
+            .bind(meta.actor)
+            .bind(meta.correlation_id)
+            .bind(meta.causation_id)
+            .bind(serde_json::to_value(&meta.headers)?);
+        if let Some(fence) = fence {
+            query = query.bind(fence);
+        }
+    }
+    match query.execute(executor).await {
+        Ok(_) => Ok(()),
+        Err(sqlx::Error::Database(e)) if is_duplicate_seq(e.as_ref()) => {
+            Err(Error::Duplicate(St::TABLE))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Violation of the unique `<TABLE>_seq_idx` index of incremental updates
+fn is_duplicate_seq(e: &dyn sqlx::error::DatabaseError) -> bool {
+    e.code().as_deref() == Some("23505") && e.constraint().is_some_and(|c| c.ends_with("_seq_idx"))
+}
@@ -0,0 +1,202 @@
+use crate::backend::{decode_row, Body, Codec, Error, Postgres, State};
+use crate::update::{HasUpdateTag, VersionedState, SNAPSHOT_TAG};
+use append_db::db::{AppendDb, AppendErr};
+use sqlx::postgres::PgListener;
+use sqlx::Row;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// Handle of the background task that keeps a follower in sync. The task is stopped
+/// when the handle is dropped.
+pub struct FollowerTask {
+    handle: JoinHandle<()>,
+}
+
+impl FollowerTask {
+    /// Stop following the leader
+    pub fn stop(self) {}
+}
+
+impl Drop for FollowerTask {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Keep the database in sync with the leader that writes to the same table with
+/// `Postgres::with_notify`. The follower listens to `Postgres::channel`, fetches rows
+/// with sequence numbers after `AppendDb::seq` and applies them with
+/// `AppendDb::apply_persisted`.
+///
+/// Rows are also fetched every `poll` period in case a notification is lost while
+/// the listener reconnects. The follower shouldn't write updates itself.
+///
+/// Updates are applied strictly in order of their sequence numbers. When an update is
+/// missing, for instance because the leader in `Durability::MemoryFirst` mode commits
+/// updates out of order, the follower stops before it and waits until it appears or a
+/// later snapshot covers it. If the update is still missing after `hole_timeout`, it is
+/// considered lost and the follower replays the storage with `AppendDb::reload_all`.
+pub async fn follow<St, C>(
+    db: &Arc<AppendDb<Postgres<St, C>>>,
+    poll: Duration,
+    hole_timeout: Duration,
+) -> Result<FollowerTask, AppendErr<Error, St::Err>>
+where
+    St: State + VersionedState + Clone + Send + Sync + 'static,
+    St::Update: HasUpdateTag + Send,
+    C: Codec,
+    C::Encoded: Body,
+{
+    let mut listener = PgListener::connect_with(&db.backend.pool)
+        .await
+        .map_err(|e| AppendErr::Backend(e.into()))?;
+    listener
+        .listen(&Postgres::<St, C>::channel())
+        .await
+        .map_err(|e| AppendErr::Backend(e.into()))?;
+    // Listen before loading, so no rows are missed in between
+    db.reload().await?;
+    Ok(FollowerTask {
+        handle: tokio::spawn(run(Arc::downgrade(db), listener, poll, hole_timeout)),
+    })
+}
+
+/// Body of the follower task. Holds only weak reference to the database, so the
+/// task ends when the database is dropped.
+async fn run<St, C>(
+    db: Weak<AppendDb<Postgres<St, C>>>,
+    mut listener: PgListener,
+    poll: Duration,
+    hole_timeout: Duration,
+) where
+    St: State + VersionedState + Clone + Send + Sync + 'static,
+    St::Update: HasUpdateTag + Send,
+    C: Codec,
+    C::Encoded: Body,
+{
+    // Missing update the follower waits for and when it was noticed
+    let mut hole: Option<(u64, Instant)> = None;
+    loop {
+        if let Ok(Err(e)) = tokio::time::timeout(poll, listener.recv()).await {
+            log::error!("Follower of {} lost notifications: {}", St::TABLE, e);
+            tokio::time::sleep(poll).await;
+        }
+        let db = match db.upgrade() {
+            Some(db) => db,
+            None => return,
+        };
+        let missing = match catch_up(&db).await {
+            Ok(missing) => missing,
+            Err(e) => {
+                log::error!("Follower of {} failed to catch up: {}", St::TABLE, e);
+                continue;
+            }
+        };
+        hole = match (missing, hole) {
+            (Some(seq), Some((waited, since))) if seq == waited => {
+                if since.elapsed() < hole_timeout {
+                    continue;
+                }
+                log::warn!(
+                    "Follower of {} gave up waiting for update {}, reloading",
+                    St::TABLE,
+                    seq
+                );
+                if let Err(e) = db.reload_all().await {
+                    log::error!("Follower of {} failed to reload: {}", St::TABLE, e);
+                }
+                None
+            }
+            (missing, _) => missing.map(|seq| (seq, Instant::now())),
+        };
+    }
+}
+
+/// Apply rows after the current sequence number up to the first missing update. The
+/// snapshot at the current sequence number is applied if the state doesn't include it yet.
+/// Returns the sequence number of the missing update if later rows wait for it.
+async fn catch_up<St, C>(
+    db: &AppendDb<Postgres<St, C>>,
+) -> Result<Option<u64>, AppendErr<Error, St::Err>>
+where
+    St: State + VersionedState + Clone + Send + Sync + 'static,
+    St::Update: HasUpdateTag + Send,
+    C: Codec,
+    C::Encoded: Body,
+{
+    let pool = &db.backend.pool;
+    loop {
+        let seq = db.seq();
+        let query = format!(
+            "select min(a.seq) + 1 as hole, (select max(seq) from {0}) as last \
+             from (select seq from {0} where tag <> '{1}' and seq > $1 union all select $1) a \
+             where not exists (select 1 from {0} b where b.tag <> '{1}' and b.seq = a.seq + 1)",
+            St::TABLE,
+            SNAPSHOT_TAG
+        );
+        let row = sqlx::query(&query)
+            .bind(seq as i64)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| AppendErr::Backend(e.into()))?;
+        let hole: i64 = row
+            .try_get("hole")
+            .map_err(|e| AppendErr::Backend(e.into()))?;
+        let last: Option<i64> = row
+            .try_get("last")
+            .map_err(|e| AppendErr::Backend(e.into()))?;
+
+        // Snapshot of the last applied update is taken after the update is fetched
+        let query = format!(
+            "select * from {0} where seq < $3 and (seq > $1 or (seq = $1 and tag = '{1}' and seq > $2)) \
+             order by seq, tag = '{1}', id",
+            St::TABLE,
+            SNAPSHOT_TAG
+        );
+        let mut rows = sqlx::query(&query)
+            .bind(seq as i64)
+            .bind(db.snapshot_seq.read_atomic() as i64)
+            .bind(hole)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| AppendErr::Backend(e.into()))?;
+        let waiting = last.is_some_and(|last| last >= hole);
+        let mut covered = false;
+        if waiting {
+            let query = format!(
+                "select * from {} where tag = '{}' and seq >= $1 order by seq, id limit 1",
+                St::TABLE,
+                SNAPSHOT_TAG
+            );
+            let snapshot = sqlx::query(&query)
+                .bind(hole)
+                .fetch_optional(pool)
+                .await
+                .map_err(|e| AppendErr::Backend(e.into()))?;
+            covered = snapshot.is_some();
+            rows.extend(snapshot);
+        }
+        let envelopes = rows
+            .iter()
+            .map(decode_row::<St, C>)
+            .collect::<Result<_, _>>()
+            .map_err(AppendErr::Backend)?;
+        let (last, next) = match db.apply_persisted(envelopes).await {
+            Err(AppendErr::Gap { last, next }) => (last, next),
+            Err(e) => return Err(e),
+            // Rows after the snapshot that covers the missing update are fetched next
+            Ok(()) if covered => continue,
+            Ok(()) => return Ok(waiting.then_some(hole as u64)),
+        };
+        // The state has changed in between, which happens only if the follower writes itself
+        log::warn!(
+            "Follower of {} missed updates between {} and {}, reloading",
+            St::TABLE,
+            last,
+            next
+        );
+        db.reload().await?;
+        return Ok(None);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod backend;
+pub mod follower;
 pub mod update;
 
 #[cfg(feature = "derive")]
@@ -7,14 +8,22 @@ pub use update::{HasUpdateTag, VersionedState};
 
 #[cfg(test)]
 mod tests {
-    use crate as append_db_postgres;
-    use crate::backend::Postgres;
-    use crate::update::{HasUpdateTag, VersionedState};
-    use append_db::backend::class::{SnapshotedUpdate, State, StateBackend};
-    use append_db::db::AppendDb;
+    use crate::backend::{Error, Pool, Postgres};
+    use crate::follower::follow;
+    use crate::update::{HasUpdateTag, UpdateBodyError, VersionedState};
+    use append_db::backend::class::{
+        Envelope, HistoryPoint, Metadata, SnapshotedUpdate, State, StateBackend,
+    };
+    use append_db::backend::group::GroupCommit;
+    use append_db::codec::{Cbor, Codec, Json, MessagePack};
+    use append_db::db::{AppendDb, AppendErr, Durability};
     use append_db_postgres_derive::*;
+    use chrono::Utc;
+    use futures::TryStreamExt;
     use serde::{Deserialize, Serialize};
     use std::convert::Infallible;
+    use std::num::NonZeroUsize;
+    use std::sync::Arc;
     use std::time::Duration;
     use tokio::time::timeout;
 
@@ -68,6 +77,32 @@ mod tests {
         }
     }
 
+    /// State stored in the table with `bytea` bodies
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, VersionedState)]
+    struct State2 {
+        field: u64,
+    }
+
+    impl State for State2 {
+        type Update = Update0;
+        type Err = Infallible;
+
+        const TABLE: &'static str = "updates_bin";
+
+        fn update(&mut self, upd: Update0) -> Result<(), Self::Err> {
+            match upd {
+                Update0::Add(v) => self.field += v,
+                Update0::Set(v) => self.field = v,
+            }
+            Ok(())
+        }
+    }
+
+    /// Drop timestamps to compare stored updates
+    fn stripped<St: State>(upds: Vec<Envelope<St>>) -> Vec<(u64, SnapshotedUpdate<St>)> {
+        upds.into_iter().map(|e| (e.seq, e.update)).collect()
+    }
+
     #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
     async fn postgres_init() {
         let state0 = State0 { field: 42 };
@@ -100,6 +135,18 @@ mod tests {
         assert_eq!(db.get().field, 4);
     }
 
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_write_ahead() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::with_durability(Postgres::new(pool), state0, Durability::WriteAhead);
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Set(4)).await.expect("update");
+        assert_eq!(db.get().field, 4);
+
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 4);
+    }
+
     #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
     async fn two_tables_test_updates() {
         let postgres = Postgres::new(pool);
@@ -133,7 +180,10 @@ mod tests {
         db.snapshot().await.expect("snapshot");
 
         let upds = db.backend.updates().await.expect("collected");
-        assert_eq!(upds, vec![SnapshotedUpdate::Snapshot(State0 { field: 43 })])
+        assert_eq!(
+            stripped(upds),
+            vec![(1, SnapshotedUpdate::Snapshot(State0 { field: 43 }))]
+        )
     }
 
     #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
@@ -145,7 +195,10 @@ mod tests {
         db.snapshot().await.expect("snapshot");
 
         let upds = db.backend.updates().await.expect("collected");
-        assert_eq!(upds, vec![SnapshotedUpdate::Snapshot(State0 { field: 43 })]);
+        assert_eq!(
+            stripped(upds),
+            vec![(1, SnapshotedUpdate::Snapshot(State0 { field: 43 }))]
+        );
 
         let postgres1 = postgres0.duplicate();
         let state1 = State1 {
@@ -158,10 +211,13 @@ mod tests {
         db1.snapshot().await.expect("snapshot");
         let upds1 = db1.backend.updates().await.expect("collected");
         assert_eq!(
-            upds1,
-            vec![SnapshotedUpdate::Snapshot(State1 {
-                field: "Hello world!".to_string()
-            })]
+            stripped(upds1),
+            vec![(
+                1,
+                SnapshotedUpdate::Snapshot(State1 {
+                    field: "Hello world!".to_string()
+                })
+            )]
         );
     }
 
@@ -249,6 +305,684 @@ mod tests {
         );
     }
 
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_update_many() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Postgres::new(pool), state0.clone());
+        db.update_many(vec![Update0::Set(4), Update0::Add(1)])
+            .await
+            .expect("update");
+        assert_eq!(db.get().field, 5);
+
+        let db = AppendDb::new(db.backend, state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 5);
+        assert_eq!(db.seq(), 2);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_update_meta() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Postgres::new(pool), state0);
+        let meta = Metadata::default()
+            .with_actor("operator")
+            .with_correlation_id("request-1")
+            .with_causation_id("command-1")
+            .with_header("ip", "127.0.0.1");
+        db.update_meta(Update0::Add(1), meta.clone())
+            .await
+            .expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let upds = db.backend.updates().await.expect("collected");
+        assert_eq!(upds[0].meta, meta);
+        assert_eq!(upds[1].meta, Metadata::default());
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_update_if() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Postgres::new(pool), state0.clone());
+        db.update_if(0, Update0::Add(1)).await.expect("update");
+        let res = db.update_if(0, Update0::Set(4)).await;
+        assert!(matches!(res, Err(AppendErr::VersionMismatch { .. })));
+
+        let db = AppendDb::new(db.backend, state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get_versioned(), (State0 { field: 43 }, 1));
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_out_of_order_writes() {
+        let postgres = Postgres::new(pool);
+        postgres
+            .write(Envelope::new(
+                2,
+                SnapshotedUpdate::Incremental(Update0::Add(1)),
+            ))
+            .await
+            .expect("write");
+        postgres
+            .write(Envelope::new(
+                1,
+                SnapshotedUpdate::Snapshot(State0 { field: 4 }),
+            ))
+            .await
+            .expect("write");
+        postgres
+            .write(Envelope::new(
+                1,
+                SnapshotedUpdate::Incremental(Update0::Set(4)),
+            ))
+            .await
+            .expect("write");
+
+        let db = AppendDb::new(postgres, State0 { field: 42 });
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 5);
+        assert_eq!(db.seq(), 2);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_updates_stream() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Postgres::new(pool), state0.clone());
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Set(4)).await.expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let upds: Vec<_> = db
+            .backend
+            .updates_stream()
+            .try_collect()
+            .await
+            .expect("collected");
+        assert_eq!(
+            stripped(upds),
+            vec![
+                (1, SnapshotedUpdate::Snapshot(State0 { field: 43 })),
+                (2, SnapshotedUpdate::Incremental(Update0::Set(4))),
+                (3, SnapshotedUpdate::Incremental(Update0::Add(1))),
+            ]
+        );
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_duplicate_seq() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Postgres::new(pool.clone()), state0.clone());
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+
+        // Writing before `load` starts numbering from the beginning
+        let db = AppendDb::new(Postgres::new(pool), state0.clone());
+        let res = db.update(Update0::Add(100)).await;
+        assert!(matches!(
+            res,
+            Err(AppendErr::Backend(Error::Duplicate("updates")))
+        ));
+
+        let db = AppendDb::new(db.backend, state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get_versioned(), (State0 { field: 43 }, 1));
+    }
+
+    async fn fill_for_compaction(db: &AppendDb<Postgres<State0>>) {
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_compact() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Postgres::new(pool), state0.clone());
+        fill_for_compaction(&db).await;
+
+        let keep = |n| NonZeroUsize::new(n).expect("non zero");
+        assert_eq!(db.compact(keep(3)).await.expect("compact"), 0);
+        assert_eq!(db.compact(keep(2)).await.expect("compact"), 1);
+        assert_eq!(db.compact(keep(1)).await.expect("compact"), 2);
+        let res = db.state_at(HistoryPoint::Seq(1)).await;
+        assert!(matches!(res, Err(AppendErr::Compacted)));
+        assert_eq!(
+            db.state_at(HistoryPoint::Seq(2))
+                .await
+                .expect("state")
+                .field,
+            44
+        );
+
+        let db = AppendDb::new(db.backend, state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 45);
+        assert_eq!(db.seq(), 3);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_compact_archive() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Postgres::new(pool.clone()).with_archive(), state0.clone());
+        fill_for_compaction(&db).await;
+
+        let keep = NonZeroUsize::new(1).expect("non zero");
+        assert_eq!(db.compact(keep).await.expect("compact"), 3);
+        let (archived,): (i64,) = sqlx::query_as("select count(*) from updates_archive")
+            .fetch_one(&pool)
+            .await
+            .expect("count");
+        assert_eq!(archived, 3);
+
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 45);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_compact_archive_fence() {
+        let state0 = State0 { field: 42 };
+        let postgres = Postgres::new(pool.clone()).with_archive();
+        let db = AppendDb::new(
+            postgres.clone().lease().await.expect("lease"),
+            state0.clone(),
+        );
+        fill_for_compaction(&db).await;
+        let keep = NonZeroUsize::new(1).expect("non zero");
+        assert_eq!(db.compact(keep).await.expect("compact"), 3);
+        let fences: Vec<(Option<i64>,)> =
+            sqlx::query_as("select fence from updates_archive order by id")
+                .fetch_all(&pool)
+                .await
+                .expect("fences");
+        assert_eq!(fences, vec![(Some(1),); 3]);
+        drop(db);
+
+        // The column is needed only with a lease
+        sqlx::query("alter table updates drop column fence")
+            .execute(&pool)
+            .await
+            .expect("alter");
+        sqlx::query("alter table updates_archive drop column fence")
+            .execute(&pool)
+            .await
+            .expect("alter");
+        let db = AppendDb::new(postgres, state0);
+        db.load().await.expect("load");
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        assert_eq!(db.compact(keep).await.expect("compact"), 3);
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 46);
+    }
+
+    async fn binary_codec<C: Codec<Encoded = Vec<u8>>>(pool: Pool) {
+        let state0 = State2 { field: 42 };
+        let db = AppendDb::new(Postgres::new(pool).with_codec::<C>(), state0.clone());
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Set(4)).await.expect("update");
+
+        let upds = db.backend.updates().await.expect("collected");
+        assert_eq!(
+            stripped(upds),
+            vec![
+                (1, SnapshotedUpdate::Snapshot(State2 { field: 43 })),
+                (2, SnapshotedUpdate::Incremental(Update0::Set(4))),
+            ]
+        );
+        let db = AppendDb::new(db.backend, state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 4);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_msgpack_codec() {
+        binary_codec::<MessagePack>(pool).await;
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_cbor_codec() {
+        binary_codec::<Cbor>(pool).await;
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_state_at() {
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Postgres::new(pool), state0.clone());
+        let before = Utc::now();
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let middle = Utc::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        db.update(Update0::Set(4)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let at = |seq| db.state_at(HistoryPoint::Seq(seq));
+        assert_eq!(at(0).await.expect("state"), state0);
+        assert_eq!(at(1).await.expect("state").field, 43);
+        assert_eq!(at(2).await.expect("state").field, 44);
+        assert_eq!(at(3).await.expect("state").field, 4);
+        assert_eq!(at(4).await.expect("state").field, 5);
+
+        let at = |t| db.state_at(HistoryPoint::Time(t));
+        assert_eq!(at(before).await.expect("state"), state0);
+        assert_eq!(at(middle).await.expect("state").field, 44);
+        assert_eq!(at(Utc::now()).await.expect("state").field, 5);
+        assert_eq!(db.get().field, 5);
+    }
+
+    /// Second version of `State0` and `Update0` with amounts in cents
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, VersionedState)]
+    #[version(1)]
+    #[upcast(from = State0)]
+    struct CentsState {
+        cents: u64,
+    }
+
+    impl From<State0> for CentsState {
+        fn from(st: State0) -> Self {
+            CentsState {
+                cents: st.field * 100,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Cents {
+        cents: u64,
+    }
+
+    #[derive(Clone, Debug, PartialEq, HasUpdateTag)]
+    #[version(1)]
+    #[upcast(from = Update0, with = cents_update)]
+    enum CentsUpdate {
+        Add(Cents),
+        Set(Cents),
+    }
+
+    fn cents_update(upd: Update0) -> CentsUpdate {
+        match upd {
+            Update0::Add(v) => CentsUpdate::Add(Cents { cents: v * 100 }),
+            Update0::Set(v) => CentsUpdate::Set(Cents { cents: v * 100 }),
+        }
+    }
+
+    impl State for CentsState {
+        type Update = CentsUpdate;
+        type Err = Infallible;
+
+        fn update(&mut self, upd: CentsUpdate) -> Result<(), Self::Err> {
+            match upd {
+                CentsUpdate::Add(v) => self.cents += v.cents,
+                CentsUpdate::Set(v) => self.cents = v.cents,
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn upcast_versions() {
+        let value = serde_json::json!(5);
+        let tag = "add".into();
+        assert_eq!(
+            CentsUpdate::deserialize_by_tag::<Json>(&tag, 0, &value).expect("upcast"),
+            CentsUpdate::Add(Cents { cents: 500 })
+        );
+        let value = serde_json::json!({ "cents": 5 });
+        assert_eq!(
+            CentsUpdate::deserialize_by_tag::<Json>(&tag, 1, &value).expect("current"),
+            CentsUpdate::Add(Cents { cents: 5 })
+        );
+        assert!(matches!(
+            CentsUpdate::deserialize_by_tag::<Json>(&tag, 2, &value),
+            Err(UpdateBodyError::UnexpectedVersion(2))
+        ));
+        assert!(matches!(
+            Update0::deserialize_by_tag::<Json>(&tag, 1, &serde_json::json!(5)),
+            Err(UpdateBodyError::UnexpectedVersion(1))
+        ));
+        assert_eq!(CentsUpdate::Add(Cents { cents: 5 }).get_version(), 1);
+
+        let value = serde_json::json!({ "field": 2 });
+        assert_eq!(
+            CentsState::deserialize_with_version::<Json>(0, &value).expect("upcast"),
+            CentsState { cents: 200 }
+        );
+        assert!(matches!(
+            CentsState::deserialize_with_version::<Json>(2, &value),
+            Err(UpdateBodyError::UnexpectedVersion(2))
+        ));
+    }
+
+    #[derive(Clone, Debug, PartialEq, HasUpdateTag)]
+    enum Shapes {
+        Reset,
+        Single(u64),
+        Pair(u64, String),
+        Transfer {
+            from: String,
+            to: String,
+            amount: u64,
+        },
+    }
+
+    fn roundtrip<C: Codec>(upd: Shapes) -> Shapes {
+        let encoded = upd.serialize_untagged::<C>().expect("serialize");
+        Shapes::deserialize_by_tag::<C>(&upd.get_tag(), 0, &encoded).expect("deserialize")
+    }
+
+    #[test]
+    fn variant_shapes() {
+        let transfer = Shapes::Transfer {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            amount: 5,
+        };
+        let cases = vec![
+            (Shapes::Reset, serde_json::json!(null)),
+            (Shapes::Single(1), serde_json::json!(1)),
+            (
+                Shapes::Pair(1, "one".to_string()),
+                serde_json::json!([1, "one"]),
+            ),
+            (
+                transfer,
+                serde_json::json!({ "from": "alice", "to": "bob", "amount": 5 }),
+            ),
+        ];
+        for (upd, json) in cases {
+            assert_eq!(upd.serialize_untagged::<Json>().expect("serialize"), json);
+            assert_eq!(roundtrip::<Json>(upd.clone()), upd);
+            assert_eq!(roundtrip::<MessagePack>(upd.clone()), upd);
+            assert_eq!(roundtrip::<Cbor>(upd.clone()), upd);
+        }
+
+        let res = Shapes::deserialize_by_tag::<Json>(&"pair".into(), 0, &serde_json::json!([1]));
+        assert!(matches!(res, Err(UpdateBodyError::Deserialize(..))));
+    }
+
+    #[derive(Clone, Debug, PartialEq, HasUpdateTag)]
+    enum Renamed {
+        #[update_tag("deposit")]
+        Add(u64),
+        #[update_tag(alias = "set")]
+        Reset(u64),
+        #[update_tag("take_snapshot", alias = "snapshot_request")]
+        Snapshot,
+    }
+
+    #[test]
+    fn custom_tags() {
+        assert_eq!(Renamed::Add(1).get_tag(), "deposit");
+        assert_eq!(Renamed::Reset(1).get_tag(), "reset");
+        assert_eq!(Renamed::Snapshot.get_tag(), "take_snapshot");
+
+        let decode =
+            |tag: &'static str, value| Renamed::deserialize_by_tag::<Json>(&tag.into(), 0, &value);
+        let one = serde_json::json!(1);
+        assert_eq!(
+            decode("deposit", one.clone()).expect("tag"),
+            Renamed::Add(1)
+        );
+        assert!(matches!(
+            decode("add", one.clone()),
+            Err(UpdateBodyError::UnknownTag(_))
+        ));
+        assert_eq!(
+            decode("reset", one.clone()).expect("tag"),
+            Renamed::Reset(1)
+        );
+        assert_eq!(decode("set", one).expect("alias"), Renamed::Reset(1));
+        assert_eq!(
+            decode("snapshot_request", serde_json::json!(null)).expect("alias"),
+            Renamed::Snapshot
+        );
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_upcast_on_load() {
+        let db = AppendDb::new(Postgres::new(pool.clone()), State0 { field: 42 });
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let db = AppendDb::new(Postgres::new(pool), CentsState { cents: 0 });
+        db.load().await.expect("load");
+        assert_eq!(db.get().cents, 4400);
+        db.update(CentsUpdate::Add(Cents { cents: 1 }))
+            .await
+            .expect("update");
+        let upds = db.backend.updates().await.expect("collected");
+        assert_eq!(upds.len(), 3);
+
+        let db = AppendDb::new(db.backend, CentsState { cents: 0 });
+        db.load().await.expect("load");
+        assert_eq!(db.get().cents, 4401);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_follower() {
+        let state0 = State0 { field: 42 };
+        let leader = AppendDb::with_durability(
+            Postgres::new(pool.clone()).with_notify(),
+            state0.clone(),
+            Durability::WriteAhead,
+        );
+        leader.update(Update0::Add(1)).await.expect("update");
+
+        let follower = Arc::new(AppendDb::new(Postgres::new(pool.clone()), state0));
+        // Polling is rare enough to check that notifications arrive
+        let _task = follow(&follower, Duration::from_secs(60), Duration::from_secs(60))
+            .await
+            .expect("follow");
+        assert_eq!(follower.get_versioned(), (State0 { field: 43 }, 1));
+
+        let mut sub = follower.subscribe();
+        leader.update(Update0::Add(1)).await.expect("update");
+        leader.snapshot().await.expect("snapshot");
+        leader
+            .update_many(vec![Update0::Set(4), Update0::Add(1)])
+            .await
+            .expect("update");
+        let mut received = vec![];
+        while received.len() < 4 {
+            let envelope = timeout(Duration::from_secs(5), sub.recv())
+                .await
+                .expect("notified")
+                .expect("received");
+            received.push(envelope.order_key());
+        }
+        assert_eq!(
+            received,
+            vec![(2, false), (2, true), (3, false), (4, false)]
+        );
+        assert_eq!(follower.get_versioned(), leader.get_versioned());
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_follower_gap() {
+        let state0 = State0 { field: 42 };
+        let postgres: Postgres<State0> = Postgres::new(pool.clone());
+        let incremental = |seq, upd| Envelope::new(seq, SnapshotedUpdate::Incremental(upd));
+        // The second update is not committed yet
+        postgres
+            .write_batch(vec![
+                incremental(1, Update0::Add(1)),
+                incremental(3, Update0::Add(1)),
+            ])
+            .await
+            .expect("write");
+        let follower = Arc::new(AppendDb::new(Postgres::new(pool), state0));
+        let _task = follow(
+            &follower,
+            Duration::from_millis(20),
+            Duration::from_secs(60),
+        )
+        .await
+        .expect("follow");
+        assert_eq!(follower.get_versioned(), (State0 { field: 43 }, 1));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(follower.seq(), 1);
+
+        // Rows without notifications are picked up by polling
+        postgres
+            .write(incremental(2, Update0::Set(10)))
+            .await
+            .expect("write");
+        let caught_up = async {
+            while follower.seq() != 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), caught_up)
+            .await
+            .expect("caught up");
+        assert_eq!(follower.get().field, 11);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_follower_lost() {
+        let state0 = State0 { field: 42 };
+        let postgres: Postgres<State0> = Postgres::new(pool.clone());
+        let incremental = |seq, upd| Envelope::new(seq, SnapshotedUpdate::Incremental(upd));
+        // The second update never appears
+        postgres
+            .write_batch(vec![
+                incremental(1, Update0::Add(1)),
+                incremental(3, Update0::Add(1)),
+                incremental(4, Update0::Add(1)),
+            ])
+            .await
+            .expect("write");
+        let follower = Arc::new(AppendDb::new(Postgres::new(pool), state0));
+        let _task = follow(
+            &follower,
+            Duration::from_millis(20),
+            Duration::from_millis(200),
+        )
+        .await
+        .expect("follow");
+        assert_eq!(follower.get_versioned(), (State0 { field: 43 }, 1));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(follower.seq(), 1);
+
+        let given_up = async {
+            while follower.seq() != 4 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), given_up)
+            .await
+            .expect("given up");
+        assert_eq!(follower.get().field, 45);
+
+        // Rows after a snapshot that covers a missing update are applied right away
+        postgres
+            .write_batch(vec![
+                incremental(6, Update0::Add(1)),
+                Envelope::new(6, SnapshotedUpdate::Snapshot(State0 { field: 100 })),
+                incremental(7, Update0::Add(1)),
+            ])
+            .await
+            .expect("write");
+        let caught_up = async {
+            while follower.seq() != 7 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(1), caught_up)
+            .await
+            .expect("caught up");
+        assert_eq!(follower.get().field, 101);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_lease() {
+        let state0 = State0 { field: 42 };
+        let first = Postgres::new(pool.clone()).lease().await.expect("lease");
+        let res = Postgres::<State0>::new(pool.clone()).try_lease().await;
+        assert!(matches!(res, Err(Error::LeaseBusy("updates"))));
+
+        let db = AppendDb::new(first, state0.clone());
+        db.update(Update0::Add(1)).await.expect("update");
+
+        // The first writer loses its lock when its connection is gone
+        let waiting = tokio::spawn(Postgres::<State0>::new(pool.clone()).lease());
+        sqlx::query(
+            "select pg_terminate_backend(pid) from pg_locks \
+             where locktype = 'advisory' and granted and pid <> pg_backend_pid() \
+             and database = (select oid from pg_database where datname = current_database())",
+        )
+        .execute(&pool)
+        .await
+        .expect("terminate");
+        let second = timeout(Duration::from_secs(5), waiting)
+            .await
+            .expect("acquired")
+            .expect("joined")
+            .expect("lease");
+
+        let res = db.update(Update0::Add(1)).await;
+        assert!(matches!(
+            res,
+            Err(AppendErr::Backend(Error::LeaseLost {
+                table: "updates",
+                fence: 1
+            }))
+        ));
+        let res = db.snapshot().await;
+        assert!(matches!(
+            res,
+            Err(AppendErr::Backend(Error::LeaseLost { .. }))
+        ));
+
+        let db = AppendDb::new(second, state0);
+        db.load().await.expect("load");
+        db.update(Update0::Add(1)).await.expect("update");
+        assert_eq!(db.get().field, 44);
+        let fences: Vec<(Option<i64>,)> = sqlx::query_as("select fence from updates order by id")
+            .fetch_all(&pool)
+            .await
+            .expect("fences");
+        assert_eq!(fences, vec![(Some(1),), (Some(2),)]);
+    }
+
+    #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
+    async fn postgres_group_commit() {
+        let state0 = State0 { field: 0 };
+        let group = GroupCommit::new(
+            Postgres::new(pool),
+            Duration::from_millis(5),
+            NonZeroUsize::new(500).expect("non zero"),
+            NonZeroUsize::new(64).expect("non zero"),
+        );
+        let db = Arc::new(AppendDb::new(group, state0.clone()));
+        let writers = (0..10).map(|_| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for _ in 0..20 {
+                    db.update(Update0::Add(1)).await.expect("update");
+                }
+            })
+        });
+        for writer in futures::future::join_all(writers).await {
+            writer.expect("writer");
+        }
+        // Larger than a single insert statement
+        db.update_many(vec![Update0::Add(1); 1500])
+            .await
+            .expect("update");
+
+        let db = AppendDb::new(db.backend.backend.duplicate(), state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get_versioned(), (State0 { field: 1700 }, 1700));
+    }
+
     #[sqlx_database_tester::test(pool(variable = "pool", migrations = "./migrations"))]
     async fn dead_lock_test() {
         let state0 = State0 { field: 0 };
@@ -0,0 +1,341 @@
+use crate::push_error;
+use convert_case::{Case, Casing};
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{FnArg, ImplItem, Pat, ReturnType, Token};
+
+/// Options of `#[state(update = pub Name, table = "updates", err = Error, derive(...))]`
+struct Options {
+    update_vis: syn::Visibility,
+    update: Option<syn::Ident>,
+    table: Option<syn::LitStr>,
+    err: Option<syn::Type>,
+    derives: Vec<syn::Path>,
+}
+
+impl Parse for Options {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut options = Options {
+            update_vis: syn::Visibility::Inherited,
+            update: None,
+            table: None,
+            err: None,
+            derives: vec![],
+        };
+        while !input.is_empty() {
+            let key: syn::Ident = input.parse()?;
+            if key == "derive" {
+                let content;
+                syn::parenthesized!(content in input);
+                let paths = Punctuated::<syn::Path, Token![,]>::parse_terminated(&content)?;
+                options.derives.extend(paths);
+            } else {
+                input.parse::<Token![=]>()?;
+                if key == "update" {
+                    options.update_vis = input.parse()?;
+                    options.update = Some(input.parse()?);
+                } else if key == "table" {
+                    options.table = Some(input.parse()?);
+                } else if key == "err" {
+                    options.err = Some(input.parse()?);
+                } else {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "expected `update`, `table`, `err` or `derive`",
+                    ));
+                }
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Methods of `AppendDb` that take precedence over methods of the generated `<State>Ext`
+/// trait with the same name, kept in sync with `append_db/src/db.rs` by a test
+const RESERVED: &[&str] = &[
+    "with_feed_capacity",
+    "get",
+    "seq",
+    "get_versioned",
+    "updates_since_snapshot",
+    "start_snapshots",
+    "subscribe",
+    "resync",
+    "get_with",
+    "update",
+    "update_many",
+    "update_meta",
+    "update_many_meta",
+    "update_if",
+    "update_with",
+    "update_with_result",
+    "snapshot",
+    "state_at",
+    "compact",
+    "load",
+    "reload",
+    "reload_all",
+    "apply_persisted",
+    "load_patched",
+];
+
+/// Method with `&mut self` receiver that becomes a variant of the update enum
+struct Handler {
+    method: syn::Ident,
+    variant: syn::Ident,
+    args: Vec<(syn::Ident, syn::Type)>,
+    /// `update_tag` attributes moved from the method to the variant
+    attrs: Vec<syn::Attribute>,
+    returns_result: bool,
+}
+
+impl Handler {
+    fn parse(method: &mut syn::ImplItemMethod) -> syn::Result<Option<Self>> {
+        let sig = &method.sig;
+        match sig.inputs.first() {
+            Some(FnArg::Receiver(r)) if r.reference.is_some() && r.mutability.is_some() => {}
+            _ => return Ok(None),
+        }
+        if let Some(asyncness) = &sig.asyncness {
+            return Err(syn::Error::new_spanned(
+                asyncness,
+                "handlers of updates cannot be async",
+            ));
+        }
+        if !sig.generics.params.is_empty() {
+            return Err(syn::Error::new_spanned(
+                &sig.generics,
+                "handlers of updates cannot be generic",
+            ));
+        }
+        let name = sig.ident.to_string();
+        if RESERVED.contains(&name.as_str()) {
+            return Err(syn::Error::new_spanned(
+                &sig.ident,
+                format!(
+                    "handler `{0}` would be shadowed by `AppendDb::{0}`, rename it",
+                    name
+                ),
+            ));
+        }
+        let mut args = vec![];
+        let mut errors = None;
+        for arg in sig.inputs.iter().skip(1) {
+            if let FnArg::Typed(arg) = arg {
+                match &*arg.pat {
+                    Pat::Ident(pat) if pat.subpat.is_none() && pat.by_ref.is_none() => {
+                        args.push((pat.ident.clone(), (*arg.ty).clone()))
+                    }
+                    pat => push_error(
+                        &mut errors,
+                        syn::Error::new_spanned(pat, "arguments of handlers must be identifiers"),
+                    ),
+                }
+            }
+        }
+        if let Some(e) = errors {
+            return Err(e);
+        }
+        let (attrs, rest) = method
+            .attrs
+            .drain(..)
+            .partition(|attr| attr.path.is_ident("update_tag"));
+        method.attrs = rest;
+        Ok(Some(Handler {
+            variant: format_ident!("{}", method.sig.ident.to_string().to_case(Case::Pascal)),
+            method: method.sig.ident.clone(),
+            args,
+            attrs,
+            returns_result: !matches!(method.sig.output, ReturnType::Default),
+        }))
+    }
+}
+
+pub(crate) fn impl_state(attr: TokenStream2, mut item: syn::ItemImpl) -> syn::Result<TokenStream2> {
+    let options: Options = syn::parse2(attr)?;
+    if let Some((_, path, _)) = &item.trait_ {
+        return Err(syn::Error::new_spanned(
+            path,
+            "#[state] expects inherent impl block of the state",
+        ));
+    }
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &item.generics,
+            "#[state] cannot be used for generic types",
+        ));
+    }
+    let state = &item.self_ty;
+    let state_name = match &**state {
+        syn::Type::Path(ty) if ty.qself.is_none() => &ty.path.segments.last().unwrap().ident,
+        ty => {
+            return Err(syn::Error::new_spanned(
+                ty,
+                "#[state] expects a named type of the state",
+            ))
+        }
+    };
+
+    let mut handlers = vec![];
+    let mut errors = None;
+    for method in item.items.iter_mut() {
+        if let ImplItem::Method(method) = method {
+            match Handler::parse(method) {
+                Ok(Some(handler)) => handlers.push(handler),
+                Ok(None) => {}
+                Err(e) => push_error(&mut errors, e),
+            }
+        }
+    }
+    if let Some(e) = errors {
+        return Err(e);
+    }
+    if handlers.is_empty() {
+        return Err(syn::Error::new(
+            item.self_ty.span(),
+            "#[state] requires at least one method with `&mut self` receiver",
+        ));
+    }
+
+    let vis = &options.update_vis;
+    let update = options
+        .update
+        .unwrap_or_else(|| format_ident!("{}Update", state_name));
+    let ext = format_ident!("{}Ext", state_name);
+    let err = options
+        .err
+        .unwrap_or_else(|| syn::parse_quote!(std::convert::Infallible));
+    let table = options.table.map(|table| {
+        quote! { const TABLE: &'static str = #table; }
+    });
+    let derives = &options.derives;
+
+    let variants = handlers.iter().map(|h| {
+        let variant = &h.variant;
+        let attrs = &h.attrs;
+        let types = h.args.iter().map(|(_, ty)| ty);
+        if h.args.is_empty() {
+            quote! { #(#attrs)* #variant }
+        } else {
+            quote! { #(#attrs)* #variant(#(#types),*) }
+        }
+    });
+    let arms = handlers.iter().map(|h| {
+        let (method, variant) = (&h.method, &h.variant);
+        let names: Vec<_> = h.args.iter().map(|(name, _)| name).collect();
+        let pattern = if names.is_empty() {
+            quote! { #update::#variant }
+        } else {
+            quote! { #update::#variant(#(#names),*) }
+        };
+        if h.returns_result {
+            quote! { #pattern => self.#method(#(#names),*), }
+        } else {
+            quote! { #pattern => { self.#method(#(#names),*); Ok(()) } }
+        }
+    });
+    let signatures: Vec<_> = handlers
+        .iter()
+        .map(|h| {
+            let method = &h.method;
+            let args = h.args.iter().map(|(name, ty)| quote! { #name: #ty });
+            quote! {
+                fn #method(&self, #(#args),*) -> impl std::future::Future<
+                    Output = Result<(), append_db::db::AppendErr<B::Err, #err>>,
+                > + Send
+            }
+        })
+        .collect();
+    let methods = handlers.iter().zip(&signatures).map(|(h, signature)| {
+        let variant = &h.variant;
+        let names = h.args.iter().map(|(name, _)| name);
+        let upd = if h.args.is_empty() {
+            quote! { #update::#variant }
+        } else {
+            quote! { #update::#variant(#(#names),*) }
+        };
+        quote! {
+            #signature {
+                self.update(#upd)
+            }
+        }
+    });
+
+    Ok(quote! {
+        #item
+
+        #[derive(Clone, Debug, PartialEq, #(#derives),*)]
+        #vis enum #update {
+            #(#variants),*
+        }
+
+        impl append_db::State for #state {
+            type Update = #update;
+            type Err = #err;
+            #table
+
+            fn update(&mut self, upd: #update) -> Result<(), #err> {
+                match upd {
+                    #(#arms)*
+                }
+            }
+        }
+
+        /// Typed updates of the state, one method per handler
+        #vis trait #ext<B: append_db::StateBackend<State = #state>> {
+            #(#signatures;)*
+        }
+
+        impl<B: append_db::StateBackend<State = #state>> #ext<B> for append_db::db::AppendDb<B> {
+            #(#methods)*
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RESERVED;
+    use syn::{FnArg, ImplItem, Item, Pat, Visibility};
+
+    #[test]
+    fn reserved_matches_append_db() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../append_db/src/db.rs");
+        let source = std::fs::read_to_string(path).expect("read db.rs");
+        let file = syn::parse_file(&source).expect("parse db.rs");
+        let mut methods = vec![];
+        for item in file.items {
+            let imp = match item {
+                Item::Impl(imp) if imp.trait_.is_none() => imp,
+                _ => continue,
+            };
+            let self_ty = &imp.self_ty;
+            if quote::quote!(#self_ty).to_string() != "AppendDb < Backend >" {
+                continue;
+            }
+            for item in imp.items {
+                if let ImplItem::Method(m) = item {
+                    let has_receiver = match m.sig.inputs.first() {
+                        Some(FnArg::Receiver(_)) => true,
+                        Some(FnArg::Typed(arg)) => {
+                            matches!(&*arg.pat, Pat::Ident(p) if p.ident == "self")
+                        }
+                        None => false,
+                    };
+                    if matches!(m.vis, Visibility::Public(_)) && has_receiver {
+                        methods.push(m.sig.ident.to_string());
+                    }
+                }
+            }
+        }
+        let mut reserved: Vec<_> = RESERVED.iter().map(|s| s.to_string()).collect();
+        methods.sort();
+        reserved.sort();
+        assert_eq!(reserved, methods);
+    }
+}
@@ -1,93 +1,281 @@
 use convert_case::{Case, Casing};
 use proc_macro::TokenStream;
-use proc_macro2::TokenStream as TokenStream2;
-use quote::{quote, quote_spanned};
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote, quote_spanned};
+use syn::parse::ParseStream;
 use syn::spanned::Spanned;
-use syn::{Data, Fields};
+use syn::{parse_macro_input, Data, Fields};
 
-#[proc_macro_derive(VersionedState)]
+mod handlers;
+
+/// Version of the type and the previous version it is upcast from, declared by
+/// `#[version(N)]` and `#[upcast(from = Previous, with = function)]` attributes.
+struct Versioning {
+    current: u16,
+    upcast: Option<Upcast>,
+}
+
+struct Upcast {
+    from: syn::Type,
+    /// Conversion from the previous version, `From` impl when not given
+    with: Option<syn::Path>,
+}
+
+impl Versioning {
+    fn parse(attrs: &[syn::Attribute]) -> syn::Result<Self> {
+        let mut versioning = Versioning {
+            current: 0,
+            upcast: None,
+        };
+        let mut upcast_attr = None;
+        for attr in attrs {
+            if attr.path.is_ident("version") {
+                versioning.current = attr.parse_args::<syn::LitInt>()?.base10_parse()?;
+            } else if attr.path.is_ident("upcast") {
+                versioning.upcast = Some(attr.parse_args_with(parse_upcast)?);
+                upcast_attr = Some(attr);
+            }
+        }
+        match upcast_attr {
+            Some(attr) if versioning.current == 0 => Err(syn::Error::new_spanned(
+                attr,
+                "#[upcast] requires #[version(N)] with N greater than 0",
+            )),
+            _ => Ok(versioning),
+        }
+    }
+
+    /// Wrap decoding of the current version with checks of the stored version.
+    /// Older versions are decoded by the previous type and converted.
+    fn dispatch(
+        &self,
+        decode_current: TokenStream2,
+        decode_previous: TokenStream2,
+    ) -> TokenStream2 {
+        let current = self.current;
+        let previous = match &self.upcast {
+            Some(Upcast { from, with }) => {
+                let with = match with {
+                    Some(with) => quote! { #with },
+                    None => quote! { <Self as std::convert::From<#from>>::from },
+                };
+                quote! {
+                    else if version < #current {
+                        #decode_previous.map(#with)
+                    }
+                }
+            }
+            None => quote! {},
+        };
+        quote! {
+            if version == #current {
+                #decode_current
+            }
+            #previous
+            else {
+                Err(append_db::update::UpdateBodyError::UnexpectedVersion(version))
+            }
+        }
+    }
+}
+
+fn parse_upcast(input: ParseStream) -> syn::Result<Upcast> {
+    let mut from = None;
+    let mut with = None;
+    while !input.is_empty() {
+        let key: syn::Ident = input.parse()?;
+        input.parse::<syn::Token![=]>()?;
+        if key == "from" {
+            from = Some(input.parse()?);
+        } else if key == "with" {
+            with = Some(input.parse()?);
+        } else {
+            return Err(syn::Error::new(key.span(), "expected `from` or `with`"));
+        }
+        if !input.is_empty() {
+            input.parse::<syn::Token![,]>()?;
+        }
+    }
+    let from = from.ok_or_else(|| input.error("expected `from = PreviousVersion`"))?;
+    Ok(Upcast { from, with })
+}
+
+#[proc_macro_derive(VersionedState, attributes(version, upcast))]
 pub fn versioned_state_derive(input: TokenStream) -> TokenStream {
     // Construct a representation of Rust code as a syntax tree
     // that we can manipulate
-    let ast = syn::parse(input).unwrap();
+    let ast = parse_macro_input!(input as syn::DeriveInput);
 
     // Build the trait implementation
     impl_versioned_state(&ast)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
 }
 
-fn impl_versioned_state(ast: &syn::DeriveInput) -> TokenStream {
+/// Generated code doesn't carry generic parameters of the type
+fn check_no_generics(ast: &syn::DeriveInput, derive: &str) -> syn::Result<()> {
+    if ast.generics.params.is_empty() {
+        Ok(())
+    } else {
+        Err(syn::Error::new_spanned(
+            &ast.generics,
+            format!("{} cannot be derived for generic types", derive),
+        ))
+    }
+}
+
+fn impl_versioned_state(ast: &syn::DeriveInput) -> syn::Result<TokenStream2> {
     let name = &ast.ident;
+    check_no_generics(ast, "VersionedState")?;
+    if let Data::Union(data) = &ast.data {
+        return Err(syn::Error::new(
+            data.union_token.span,
+            "VersionedState cannot be derived for unions",
+        ));
+    }
+    let versioning = Versioning::parse(&ast.attrs)?;
+    let current = versioning.current;
+    let body = versioning.dispatch(
+        quote! {
+            C::decode(value).map_err(|e| {
+                append_db::update::UpdateBodyError::Deserialize(version, std::borrow::Cow::Borrowed(append_db::update::SNAPSHOT_TAG), e, C::describe(value))
+            })
+        },
+        versioning.upcast.as_ref().map_or_else(TokenStream2::new, |upcast| {
+            let from = &upcast.from;
+            quote! { <#from as append_db::update::VersionedState>::deserialize_with_version::<C>(version, value) }
+        }),
+    );
     let gen = quote! {
         impl VersionedState for #name {
-            fn deserialize_with_version(
+            fn deserialize_with_version<C: append_db::codec::Codec>(
                 version: u16,
-                value: serde_json::Value,
-            ) -> Result<Self, append_db_postgres::update::UpdateBodyError> {
-                serde_json::from_value(value.clone()).map_err(|e| {
-                    append_db_postgres::update::UpdateBodyError::Deserialize(version, std::borrow::Cow::Borrowed(append_db_postgres::update::SNAPSHOT_TAG), e, value)
-                })
+                value: &C::Encoded,
+            ) -> Result<Self, append_db::update::UpdateBodyError> {
+                #body
             }
             fn get_version(&self) -> u16 {
-                0
+                #current
             }
-            #[allow(clippy::needless_question_mark)]
-            fn serialize(&self) -> Result<serde_json::Value, append_db_postgres::update::UpdateBodyError> {
-                Ok(serde_json::to_value(&self)
-                    .map_err(|e| append_db_postgres::update::UpdateBodyError::Serialize(std::borrow::Cow::Borrowed(append_db_postgres::update::SNAPSHOT_TAG), e))?)
+            fn serialize<C: append_db::codec::Codec>(&self) -> Result<C::Encoded, append_db::update::UpdateBodyError> {
+                C::encode(self)
+                    .map_err(|e| append_db::update::UpdateBodyError::Serialize(std::borrow::Cow::Borrowed(append_db::update::SNAPSHOT_TAG), e))
             }
         }
     };
-    gen.into()
+    Ok(gen)
+}
+
+/// Generates `State` implementation from an inherent impl block of the state. Every
+/// method with `&mut self` receiver becomes a variant of the update enum, e.g. `fn add(&mut self, v: u64)`
+/// handles `Update::Add(u64)`. Handlers return either nothing or `Result<(), Err>`.
+///
+/// Options: `update = pub Name` of the generated enum (`<State>Update` by default), `table = "..."`,
+/// `err = Type` (`Infallible` by default) and `derive(...)` with extra derives for the enum.
+/// Also generates `<State>Ext` trait with typed methods on `AppendDb`, like `db.add(5).await`.
+#[proc_macro_attribute]
+pub fn state(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as syn::ItemImpl);
+    handlers::impl_state(attr.into(), item)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
 }
 
-#[proc_macro_derive(HasUpdateTag)]
+#[proc_macro_derive(HasUpdateTag, attributes(version, upcast, update_tag))]
 pub fn has_update_tag_derive(input: TokenStream) -> TokenStream {
     // Construct a representation of Rust code as a syntax tree
     // that we can manipulate
-    let ast = syn::parse(input).unwrap();
+    let ast = parse_macro_input!(input as syn::DeriveInput);
 
     // Build the trait implementation
     impl_has_update_tag(&ast)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
 }
 
-fn impl_has_update_tag(ast: &syn::DeriveInput) -> TokenStream {
+fn impl_has_update_tag(ast: &syn::DeriveInput) -> syn::Result<TokenStream2> {
     let name = &ast.ident;
-    let data = &ast.data;
+    check_no_generics(ast, "HasUpdateTag")?;
+    let variants = enum_variants(ast)?;
+    let versioning = Versioning::parse(&ast.attrs)?;
+    let current = versioning.current;
+    let tags = variant_tags(&variants)?;
 
-    let deserialize_by_tag_body = impl_deserialize_by_tag(name, data);
-    let impl_get_tag_body = impl_get_tag(name, data);
-    let impl_serialize_untagged_body = impl_serialize_untagged(name, data);
+    let deserialize_by_tag_body = versioning.dispatch(
+        impl_deserialize_by_tag(name, &variants, &tags),
+        versioning.upcast.as_ref().map_or_else(TokenStream2::new, |upcast| {
+            let from = &upcast.from;
+            quote! { <#from as append_db::update::HasUpdateTag>::deserialize_by_tag::<C>(tag, version, value) }
+        }),
+    );
+    let impl_get_tag_body = impl_get_tag(name, &variants, &tags);
+    let impl_serialize_untagged_body = impl_serialize_untagged(name, &variants);
 
     let gen = quote! {
         impl HasUpdateTag for #name {
-            fn deserialize_by_tag(
-                tag: &append_db_postgres::update::UpdateTag,
+            fn deserialize_by_tag<C: append_db::codec::Codec>(
+                tag: &append_db::update::UpdateTag,
                 version: u16,
-                value: serde_json::Value,
-            ) -> Result<Self, append_db_postgres::update::UpdateBodyError>
+                value: &C::Encoded,
+            ) -> Result<Self, append_db::update::UpdateBodyError>
             where
                 Self: std::marker::Sized,
             {
                 #deserialize_by_tag_body
             }
-            fn get_tag(&self) -> append_db_postgres::update::UpdateTag {
+            fn get_tag(&self) -> append_db::update::UpdateTag {
                 #impl_get_tag_body
             }
             fn get_version(&self) -> u16 {
-                0
+                #current
             }
-            fn serialize_untagged(&self) -> Result<serde_json::Value, append_db_postgres::update::UpdateBodyError> {
+            fn serialize_untagged<C: append_db::codec::Codec>(&self) -> Result<C::Encoded, append_db::update::UpdateBodyError> {
                 #impl_serialize_untagged_body
             }
         }
     };
-    gen.into()
+    Ok(gen)
 }
 
-fn enum_tags(data: &syn::Data) -> Vec<syn::Ident> {
-    match data {
-        Data::Enum(data_enum) => data_enum.variants.iter().map(|x| x.ident.clone()).collect(),
-        _ => panic!("HasUpdateTag is only implemented for enums"),
+/// Variants of the enum. Fields of the variants must own their data as updates are
+/// decoded from the storage.
+fn enum_variants(ast: &syn::DeriveInput) -> syn::Result<Vec<&syn::Variant>> {
+    let data_enum = match &ast.data {
+        Data::Enum(data_enum) => data_enum,
+        Data::Struct(data) => {
+            return Err(syn::Error::new(
+                data.struct_token.span,
+                "HasUpdateTag can only be derived for enums, use VersionedState for structs",
+            ))
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new(
+                data.union_token.span,
+                "HasUpdateTag can only be derived for enums",
+            ))
+        }
+    };
+    if data_enum.variants.is_empty() {
+        return Err(syn::Error::new(
+            ast.ident.span(),
+            "HasUpdateTag requires at least one variant",
+        ));
+    }
+    let mut errors = None;
+    for variant in data_enum.variants.iter() {
+        for field in variant.fields.iter() {
+            if let syn::Type::Reference(ty) = &field.ty {
+                let e = syn::Error::new_spanned(
+                    ty,
+                    "fields of updates cannot be references, use owned types",
+                );
+                push_error(&mut errors, e);
+            }
+        }
+    }
+    match errors {
+        Some(e) => Err(e),
+        None => Ok(data_enum.variants.iter().collect()),
     }
 }
 
@@ -95,78 +283,233 @@ fn enum_tag(name: &syn::Ident) -> String {
     name.to_string().to_case(Case::Snake)
 }
 
-fn impl_deserialize_by_tag(name: &syn::Ident, data: &syn::Data) -> TokenStream2 {
-    let tags = enum_tags(data);
+/// Must match `append_db::update::SNAPSHOT_TAG`
+const SNAPSHOT_TAG: &str = "snapshot";
+
+/// Tag of the variant and older tags that are still accepted on decoding. Declared
+/// by `#[update_tag("tag")]` and `#[update_tag(alias = "old_tag")]`, the snake cased
+/// name of the variant by default.
+struct VariantTag {
+    tag: String,
+    aliases: Vec<String>,
+}
+
+impl VariantTag {
+    fn parse(variant: &syn::Variant) -> syn::Result<Self> {
+        let mut tag = None;
+        let mut aliases = vec![];
+        for attr in variant.attrs.iter() {
+            if !attr.path.is_ident("update_tag") {
+                continue;
+            }
+            attr.parse_args_with(|input: ParseStream| {
+                while !input.is_empty() {
+                    if input.peek(syn::LitStr) {
+                        let lit: syn::LitStr = input.parse()?;
+                        if tag.is_some() {
+                            return Err(syn::Error::new(lit.span(), "tag is already given"));
+                        }
+                        tag = Some(lit);
+                    } else {
+                        let key: syn::Ident = input.parse()?;
+                        if key != "alias" {
+                            return Err(syn::Error::new(
+                                key.span(),
+                                "expected tag string or `alias = \"...\"`",
+                            ));
+                        }
+                        input.parse::<syn::Token![=]>()?;
+                        aliases.push(input.parse::<syn::LitStr>()?);
+                    }
+                    if !input.is_empty() {
+                        input.parse::<syn::Token![,]>()?;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+
+        let mut errors: Option<syn::Error> = None;
+        let mut check = |value: &str, span: Span| {
+            if value == SNAPSHOT_TAG {
+                let e = syn::Error::new(
+                    span,
+                    format!(
+                        "tag '{}' is reserved for snapshots, set another one with #[update_tag(\"...\")]",
+                        SNAPSHOT_TAG
+                    ),
+                );
+                push_error(&mut errors, e);
+            }
+        };
+        let tag = match tag {
+            Some(lit) => {
+                check(&lit.value(), lit.span());
+                lit.value()
+            }
+            None => {
+                let tag = enum_tag(&variant.ident);
+                check(&tag, variant.ident.span());
+                tag
+            }
+        };
+        for alias in aliases.iter() {
+            check(&alias.value(), alias.span());
+        }
+        match errors {
+            Some(e) => Err(e),
+            None => Ok(VariantTag {
+                tag,
+                aliases: aliases.iter().map(|a| a.value()).collect(),
+            }),
+        }
+    }
+}
+
+/// Collect errors to report all of them at once
+fn push_error(errors: &mut Option<syn::Error>, e: syn::Error) {
+    match errors.as_mut() {
+        Some(errors) => errors.combine(e),
+        None => *errors = Some(e),
+    }
+}
+
+/// Tags of all variants, checked for duplicates
+fn variant_tags(variants: &[&syn::Variant]) -> syn::Result<Vec<VariantTag>> {
+    let mut tags: Vec<VariantTag> = vec![];
+    let mut errors: Option<syn::Error> = None;
+    let mut seen: Vec<(String, &syn::Ident)> = vec![];
+    for variant in variants {
+        let tag = match VariantTag::parse(variant) {
+            Ok(tag) => tag,
+            Err(e) => {
+                push_error(&mut errors, e);
+                continue;
+            }
+        };
+        for value in std::iter::once(&tag.tag).chain(tag.aliases.iter()) {
+            if let Some((_, other)) = seen.iter().find(|(v, _)| v == value) {
+                let e = syn::Error::new(
+                    variant.ident.span(),
+                    format!("tag '{}' is already used by variant `{}`", value, other),
+                );
+                push_error(&mut errors, e);
+            }
+            seen.push((value.clone(), &variant.ident));
+        }
+        tags.push(tag);
+    }
+    match errors {
+        Some(e) => Err(e),
+        None => Ok(tags),
+    }
+}
+
+/// Names of tuple fields to bind them in patterns
+fn tuple_bindings(fields: &syn::FieldsUnnamed) -> Vec<syn::Ident> {
+    (0..fields.unnamed.len())
+        .map(|i| format_ident!("field{}", i))
+        .collect()
+}
+
+/// Decode body of the variant. Unit variants are stored as `()`, variants with a
+/// single field as the field itself, tuple variants as tuples and variants with
+/// named fields as structs.
+fn decode_variant(name: &syn::Ident, variant: &syn::Variant) -> TokenStream2 {
+    let variant_name = &variant.ident;
+    let decoded = quote! {
+        C::decode(value).map_err(|e| {
+            append_db::update::UpdateBodyError::Deserialize(version, tag.to_owned(), e, C::describe(value))
+        })?
+    };
+    match &variant.fields {
+        Fields::Unit => quote! {
+            let () = #decoded;
+            Ok(#name::#variant_name)
+        },
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => quote! {
+            Ok(#name::#variant_name(#decoded))
+        },
+        Fields::Unnamed(fields) => {
+            let bindings = tuple_bindings(fields);
+            let types = fields.unnamed.iter().map(|f| &f.ty);
+            quote! {
+                let (#(#bindings,)*): (#(#types,)*) = #decoded;
+                Ok(#name::#variant_name(#(#bindings),*))
+            }
+        }
+        Fields::Named(fields) => {
+            let names: Vec<_> = fields.named.iter().map(|f| &f.ident).collect();
+            let types = fields.named.iter().map(|f| &f.ty);
+            quote! {
+                #[derive(append_db::serde::Deserialize)]
+                #[serde(crate = "append_db::serde")]
+                struct Body {
+                    #(#names: #types),*
+                }
+                let Body { #(#names),* } = #decoded;
+                Ok(#name::#variant_name { #(#names),* })
+            }
+        }
+    }
+}
+
+fn impl_deserialize_by_tag(
+    name: &syn::Ident,
+    variants: &[&syn::Variant],
+    tags: &[VariantTag],
+) -> TokenStream2 {
     let mut variant_checkers = TokenStream2::new();
-    for (i, tag) in tags.iter().enumerate() {
-        let tag_str = enum_tag(tag);
+    for (i, (variant, tag)) in variants.iter().zip(tags).enumerate() {
+        let tag_strs = std::iter::once(&tag.tag).chain(tag.aliases.iter());
+        let decode = decode_variant(name, variant);
         if i == 0 {
             variant_checkers.extend(quote! {
-                if tag == #tag_str {
-                    Ok(#name::#tag(
-                        serde_json::from_value(value.clone()).map_err(|e| {
-                            append_db_postgres::update::UpdateBodyError::Deserialize(version, tag.to_owned(), e, value)
-                        })?,
-                    ))
+                if #(tag == #tag_strs)||* {
+                    #decode
                 }
             })
         } else {
             variant_checkers.extend(quote! {
-                else if tag == #tag_str {
-                    Ok(#name::#tag(
-                        serde_json::from_value(value.clone()).map_err(|e| {
-                            append_db_postgres::update::UpdateBodyError::Deserialize(version, tag.to_owned(), e, value)
-                        })?,
-                    ))
+                else if #(tag == #tag_strs)||* {
+                    #decode
                 }
             })
         }
     }
-    if !tags.is_empty() {
-        variant_checkers.extend(quote! {
-            else {
-                Err(append_db_postgres::update::UpdateBodyError::UnknownTag(append_db_postgres::update::UnknownUpdateTag(
-                    tag.to_string(),
-                )))
-            }
-        });
-    } else {
-        variant_checkers.extend(quote! {
-            Err(append_db_postgres::update::UpdateBodyError::UnknownTag(append_db_postgres::update::UnknownUpdateTag(
+    variant_checkers.extend(quote! {
+        else {
+            Err(append_db::update::UpdateBodyError::UnknownTag(append_db::update::UnknownUpdateTag(
                 tag.to_string(),
             )))
-        });
-    }
+        }
+    });
 
     variant_checkers
 }
 
-fn impl_get_tag(name: &syn::Ident, data: &syn::Data) -> TokenStream2 {
+fn impl_get_tag(
+    name: &syn::Ident,
+    variants: &[&syn::Variant],
+    tags: &[VariantTag],
+) -> TokenStream2 {
     let mut matches = TokenStream2::new();
-    match data {
-        Data::Enum(data_enum) if data_enum.variants.is_empty() => matches.extend(quote! {
-            _ => todo!(),
-        }),
-        Data::Enum(data_enum) => {
-            for variant in data_enum.variants.iter() {
-                // Variant's name
-                let variant_name = &variant.ident;
-                // Variant can have unnamed fields like `Variant(i32, i64)`
-                // Variant can have named fields like `Variant {x: i32, y: i32}`
-                // Variant can be named Unit like `Variant`
-                let fields_in_variant = match &variant.fields {
-                    Fields::Unnamed(_) => quote_spanned! {variant.span()=> (..) },
-                    Fields::Unit => quote_spanned! { variant.span()=> },
-                    Fields::Named(_) => quote_spanned! {variant.span()=> {..} },
-                };
-                let tag_str = enum_tag(variant_name);
+    for (variant, tag) in variants.iter().zip(tags) {
+        // Variant's name
+        let variant_name = &variant.ident;
+        // Variant can have unnamed fields like `Variant(i32, i64)`
+        // Variant can have named fields like `Variant {x: i32, y: i32}`
+        // Variant can be named Unit like `Variant`
+        let fields_in_variant = match &variant.fields {
+            Fields::Unnamed(_) => quote_spanned! {variant.span()=> (..) },
+            Fields::Unit => quote_spanned! { variant.span()=> },
+            Fields::Named(_) => quote_spanned! {variant.span()=> {..} },
+        };
+        let tag_str = &tag.tag;
 
-                matches.extend(quote! {
-                    #name::#variant_name #fields_in_variant => std::borrow::Cow::Borrowed(#tag_str),
-                })
-            }
-        }
-        _ => panic!("HasUpdateTag is only implemented for enums"),
+        matches.extend(quote! {
+            #name::#variant_name #fields_in_variant => std::borrow::Cow::Borrowed(#tag_str),
+        })
     }
 
     quote! {
@@ -176,32 +519,45 @@ fn impl_get_tag(name: &syn::Ident, data: &syn::Data) -> TokenStream2 {
     }
 }
 
-fn impl_serialize_untagged(name: &syn::Ident, data: &syn::Data) -> TokenStream2 {
+fn impl_serialize_untagged(name: &syn::Ident, variants: &[&syn::Variant]) -> TokenStream2 {
     let mut matches = TokenStream2::new();
-    match data {
-        Data::Enum(data_enum) if data_enum.variants.is_empty() => matches.extend(quote! {
-            _ => todo!(),
-        }),
-        Data::Enum(data_enum) => {
-            for variant in data_enum.variants.iter() {
-                // Variant's name
-                let variant_name = &variant.ident;
-                let fields_in_variant = match &variant.fields {
-                    Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
-                        quote_spanned! {variant.span()=> (v) }
-                    }
-                    _ => panic!(
-                        "HasUpdateTag deriving requires that every enum has one unamed field"
-                    ),
-                };
-
-                matches.extend(quote! {
-                    #name::#variant_name #fields_in_variant => Ok(serde_json::to_value(&v)
-                    .map_err(|e| append_db_postgres::update::UpdateBodyError::Serialize(self.get_tag(), e))?),
-                })
+    for variant in variants {
+        // Variant's name
+        let variant_name = &variant.ident;
+        let (fields_in_variant, encoded) = match &variant.fields {
+            Fields::Unit => (quote! {}, quote! { C::encode(&()) }),
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => (
+                quote_spanned! {variant.span()=> (v) },
+                quote! { C::encode(v) },
+            ),
+            Fields::Unnamed(fields) => {
+                let bindings = tuple_bindings(fields);
+                (
+                    quote! { (#(#bindings),*) },
+                    quote! { C::encode(&(#(#bindings,)*)) },
+                )
             }
-        }
-        _ => panic!("HasUpdateTag is only implemented for enums"),
+            Fields::Named(fields) => {
+                let names: Vec<_> = fields.named.iter().map(|f| &f.ident).collect();
+                let types = fields.named.iter().map(|f| &f.ty);
+                (
+                    quote! { { #(#names),* } },
+                    quote! {{
+                        #[derive(append_db::serde::Serialize)]
+                        #[serde(crate = "append_db::serde")]
+                        struct Body<'a> {
+                            #(#names: &'a #types),*
+                        }
+                        C::encode(&Body { #(#names),* })
+                    }},
+                )
+            }
+        };
+
+        matches.extend(quote! {
+            #name::#variant_name #fields_in_variant => #encoded
+            .map_err(|e| append_db::update::UpdateBodyError::Serialize(self.get_tag(), e)),
+        })
     }
 
     quote! {
@@ -0,0 +1,83 @@
+use append_db::backend::class::{SnapshotedUpdate, StateBackend};
+use append_db::backend::memory::InMemory;
+use append_db::db::{AppendDb, AppendErr};
+use append_db::update::HasUpdateTag;
+use append_db_postgres_derive::{state, HasUpdateTag};
+use std::fmt;
+
+#[derive(Debug)]
+struct Underflow;
+
+impl fmt::Display for Underflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Counter would go below zero")
+    }
+}
+
+impl std::error::Error for Underflow {}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Counter {
+    value: u64,
+    label: String,
+}
+
+#[state(update = CounterUpdate, table = "updates2", err = Underflow, derive(HasUpdateTag))]
+impl Counter {
+    fn add(&mut self, v: u64) {
+        self.value += v;
+    }
+
+    fn sub(&mut self, v: u64) -> Result<(), Underflow> {
+        self.value = self.value.checked_sub(v).ok_or(Underflow)?;
+        Ok(())
+    }
+
+    #[update_tag("relabel")]
+    fn set_label(&mut self, label: String, reset: bool) {
+        self.label = label;
+        if reset {
+            self.value = 0;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.value = 0;
+    }
+}
+
+#[tokio::test]
+async fn state_handlers() {
+    let counter = Counter {
+        value: 0,
+        label: String::new(),
+    };
+    let db = AppendDb::new(InMemory::new(), counter.clone());
+    db.add(5).await.expect("update");
+    db.set_label("apples".to_string(), false)
+        .await
+        .expect("update");
+    assert!(matches!(db.sub(6).await, Err(AppendErr::Update(Underflow))));
+    db.sub(2).await.expect("update");
+    assert_eq!(db.get().value, 3);
+    db.reset().await.expect("update");
+
+    let upds = db.backend.updates().await.expect("collected");
+    let relabel = CounterUpdate::SetLabel("apples".to_string(), false);
+    assert_eq!(relabel.get_tag(), "relabel");
+    assert_eq!(upds[1].update, SnapshotedUpdate::Incremental(relabel));
+    assert_eq!(
+        upds[3].update,
+        SnapshotedUpdate::Incremental(CounterUpdate::Reset)
+    );
+
+    let db = AppendDb::new(db.backend, counter);
+    db.load().await.expect("load");
+    assert_eq!(
+        db.get(),
+        Counter {
+            value: 0,
+            label: "apples".to_string()
+        }
+    );
+}
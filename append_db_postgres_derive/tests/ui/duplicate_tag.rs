@@ -0,0 +1,15 @@
+use append_db_postgres_derive::HasUpdateTag;
+
+#[derive(HasUpdateTag)]
+enum Update {
+    Set(u64),
+    #[update_tag("set")]
+    Replace(u64),
+    #[update_tag(alias = "set")]
+    Assign(u64),
+    Add(u64),
+    #[update_tag("increment", alias = "add")]
+    Increment(u64),
+}
+
+fn main() {}
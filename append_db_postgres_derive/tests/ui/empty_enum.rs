@@ -0,0 +1,6 @@
+use append_db_postgres_derive::HasUpdateTag;
+
+#[derive(HasUpdateTag)]
+enum Update {}
+
+fn main() {}
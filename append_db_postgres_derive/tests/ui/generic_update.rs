@@ -0,0 +1,8 @@
+use append_db_postgres_derive::HasUpdateTag;
+
+#[derive(HasUpdateTag)]
+enum Update<T> {
+    Set(T),
+}
+
+fn main() {}
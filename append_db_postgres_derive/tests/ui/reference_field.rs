@@ -0,0 +1,9 @@
+use append_db_postgres_derive::HasUpdateTag;
+
+#[derive(HasUpdateTag)]
+enum Update {
+    Add(u64),
+    Rename { from: &'static str, to: String },
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use append_db_postgres_derive::HasUpdateTag;
+
+#[derive(HasUpdateTag)]
+enum Update {
+    Snapshot(u64),
+    #[update_tag("snapshot")]
+    Set(u64),
+    #[update_tag(alias = "snapshot")]
+    Add(u64),
+}
+
+fn main() {}
@@ -0,0 +1,20 @@
+use append_db_postgres_derive::state;
+
+struct Pair {
+    left: u64,
+    right: u64,
+}
+
+#[state]
+impl Pair {
+    fn set(&mut self, (left, right): (u64, u64)) {
+        self.left = left;
+        self.right = right;
+    }
+
+    async fn reset(&mut self) {
+        self.left = 0;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,19 @@
+use append_db_postgres_derive::state;
+
+#[derive(Clone)]
+struct Counter {
+    value: u64,
+}
+
+#[state]
+impl Counter {
+    fn add(&mut self, v: u64) {
+        self.value += v;
+    }
+
+    fn update(&mut self, v: u64) {
+        self.value = v;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use append_db_postgres_derive::HasUpdateTag;
+
+#[derive(HasUpdateTag)]
+struct Update {
+    field: u64,
+}
+
+fn main() {}
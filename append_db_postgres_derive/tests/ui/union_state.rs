@@ -0,0 +1,8 @@
+use append_db_postgres_derive::VersionedState;
+
+#[derive(VersionedState)]
+union State {
+    field: u64,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use append_db_postgres_derive::VersionedState;
+
+struct Old;
+
+#[derive(VersionedState)]
+#[upcast(from = Old)]
+struct State {
+    field: u64,
+}
+
+fn main() {}
@@ -0,0 +1,53 @@
+create table updates(
+    id integer primary key autoincrement,
+    created timestamp not null,
+    seq integer not null,
+    version integer not null,
+    tag text not null,
+    body text not null,
+    actor text,
+    correlation_id text,
+    causation_id text,
+    headers text not null default '{}'
+);
+create index updates_seq_idx on updates(seq);
+
+create table updates2(
+    id integer primary key autoincrement,
+    created timestamp not null,
+    seq integer not null,
+    version integer not null,
+    tag text not null,
+    body text not null,
+    actor text,
+    correlation_id text,
+    causation_id text,
+    headers text not null default '{}'
+);
+create index updates2_seq_idx on updates2(seq);
+
+create table updates_archive(
+    id integer primary key,
+    created timestamp not null,
+    seq integer not null,
+    version integer not null,
+    tag text not null,
+    body text not null,
+    actor text,
+    correlation_id text,
+    causation_id text,
+    headers text not null default '{}'
+);
+
+create table updates2_archive(
+    id integer primary key,
+    created timestamp not null,
+    seq integer not null,
+    version integer not null,
+    tag text not null,
+    body text not null,
+    actor text,
+    correlation_id text,
+    causation_id text,
+    headers text not null default '{}'
+);
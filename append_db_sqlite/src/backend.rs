@@ -0,0 +1,286 @@
+pub use append_db::backend::class::{
+    Envelope, HistoryPoint, Metadata, SnapshotedUpdate, State, StateBackend,
+};
+use append_db::codec::Json;
+use append_db::update::{HasUpdateTag, UpdateBodyError, VersionedState, SNAPSHOT_TAG};
+use async_stream::try_stream;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use sqlx::sqlite::SqliteRow;
+use sqlx::Row;
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::num::NonZeroUsize;
+use thiserror::Error;
+
+/// Connection pool to SQLite
+pub type Pool = sqlx::Pool<sqlx::Sqlite>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Failed to decode body by tag: {0}")]
+    UpdateBody(#[from] UpdateBodyError),
+    #[error("Failed to decode/encode JSON: {0}")]
+    Encoding(#[from] serde_json::Error),
+}
+
+/// Storage in SQLite database with the same layout as `append_db_postgres::backend::Postgres`.
+/// Bodies are encoded by `Json` codec and stored as text.
+#[derive(Clone)]
+pub struct Sqlite<St: State> {
+    /// The pool is shared by clones and duplicates. Keep the default WAL journal mode of
+    /// `SqliteConnectOptions`, so replaying readers don't block writers.
+    pub pool: Pool,
+    /// Move compacted rows into `<TABLE>_archive` table instead of deleting them
+    pub archive: bool,
+    pub state_proxy: PhantomData<St>,
+}
+
+impl<St: State> Sqlite<St> {
+    pub fn new(pool: Pool) -> Self {
+        Sqlite {
+            pool,
+            archive: false,
+            state_proxy: PhantomData,
+        }
+    }
+
+    /// Keep compacted rows in `<TABLE>_archive` table with the same layout as the
+    /// table of the state.
+    pub fn with_archive(mut self) -> Self {
+        self.archive = true;
+        self
+    }
+
+    /// Duplicates a connection to the same pool, casting St to St2
+    pub fn duplicate<St2: State>(&self) -> Sqlite<St2> {
+        Sqlite {
+            pool: self.pool.clone(),
+            archive: self.archive,
+            state_proxy: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<
+        Upd: HasUpdateTag + Send,
+        St: State<Update = Upd> + VersionedState + Clone + Send + Sync + 'static,
+    > StateBackend for Sqlite<St>
+{
+    type State = St;
+    type Err = Error;
+
+    async fn write(&self, envelope: Envelope<St>) -> Result<(), Self::Err> {
+        insert(&self.pool, envelope).await
+    }
+
+    async fn write_batch(&self, envelopes: Vec<Envelope<St>>) -> Result<(), Self::Err> {
+        let mut tx = self.pool.begin().await?;
+        for envelope in envelopes {
+            insert(&mut tx, envelope).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn updates(&self) -> Result<Vec<Envelope<St>>, Self::Err> {
+        self.updates_stream().try_collect().await
+    }
+
+    fn updates_stream(&self) -> BoxStream<'_, Result<Envelope<St>, Self::Err>> {
+        Box::pin(try_stream! {
+                let query = format!(
+                "select id, seq from {} where tag = '{}' order by seq desc, id desc limit 1",
+                St::TABLE,
+                SNAPSHOT_TAG
+            );
+            let snapshot = sqlx::query(&query).fetch_optional(&self.pool).await?;
+            let (snapshot_id, snapshot_seq): (i64, i64) = match snapshot {
+                Some(r) => (r.try_get("id")?, r.try_get("seq")?),
+                None => (-1, 0),
+            };
+
+            // The snapshot goes first as all other rows have greater sequence numbers
+            let query = format!(
+                "select * from {} where id = $1 or (tag <> '{}' and seq > $2) order by seq, id",
+                St::TABLE,
+                SNAPSHOT_TAG
+            );
+            let mut rows = sqlx::query(&query)
+                .bind(snapshot_id)
+                .bind(snapshot_seq)
+                .fetch(&self.pool);
+            while let Some(r) = rows.try_next().await? {
+                yield decode_row(&r)?;
+            }
+        })
+    }
+
+    async fn compact(&self, keep_snapshots: NonZeroUsize) -> Result<u64, Self::Err> {
+        let query = format!(
+            "select id, seq from {} where tag = '{}' order by seq desc, id desc limit 1 offset $1",
+            St::TABLE,
+            SNAPSHOT_TAG
+        );
+        let snapshot = sqlx::query(&query)
+            .bind(keep_snapshots.get() as i64 - 1)
+            .fetch_optional(&self.pool)
+            .await?;
+        let (snapshot_id, snapshot_seq): (i64, i64) = match snapshot {
+            Some(r) => (r.try_get("id")?, r.try_get("seq")?),
+            None => return Ok(0),
+        };
+
+        // Everything that goes before the snapshot in `Envelope::order_key` order
+        let obsolete = format!(
+            "seq < $1 or (seq = $1 and (tag <> '{0}' or id < $2))",
+            SNAPSHOT_TAG
+        );
+        let mut tx = self.pool.begin().await?;
+        if self.archive {
+            let query = format!(
+                "insert into {0}_archive ({2}) select {2} from {0} where {1}",
+                St::TABLE,
+                obsolete,
+                "id, created, seq, version, tag, body, actor, correlation_id, causation_id, headers"
+            );
+            sqlx::query(&query)
+                .bind(snapshot_seq)
+                .bind(snapshot_id)
+                .execute(&mut tx)
+                .await?;
+        }
+        let query = format!("delete from {} where {}", St::TABLE, obsolete);
+        let res = sqlx::query(&query)
+            .bind(snapshot_seq)
+            .bind(snapshot_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn updates_until(
+        &self,
+        point: HistoryPoint,
+    ) -> Result<Option<Vec<Envelope<St>>>, Self::Err> {
+        let query = format!(
+            "select * from {} order by seq, tag = '{}', id limit 1",
+            St::TABLE,
+            SNAPSHOT_TAG
+        );
+        let oldest = sqlx::query(&query).fetch_optional(&self.pool).await?;
+        if let Some(r) = oldest {
+            if !point.is_retained(&decode_row::<St>(&r)?) {
+                return Ok(None);
+            }
+        }
+        let last_seq: i64 = match point {
+            HistoryPoint::Seq(seq) => seq as i64,
+            HistoryPoint::Time(t) => {
+                let query = format!(
+                    "select coalesce(max(seq), 0) as seq from {} where created <= $1",
+                    St::TABLE
+                );
+                sqlx::query(&query)
+                    .bind(t.naive_utc())
+                    .fetch_one(&self.pool)
+                    .await?
+                    .try_get("seq")?
+            }
+        };
+
+        let query = format!(
+            "select * from {} where tag = '{}' and seq <= $1 order by seq desc, id desc limit 1",
+            St::TABLE,
+            SNAPSHOT_TAG
+        );
+        let snapshot = sqlx::query(&query)
+            .bind(last_seq)
+            .fetch_optional(&self.pool)
+            .await?;
+        let mut parsed: Vec<Envelope<St>> = vec![];
+        let mut first_seq = 0;
+        if let Some(r) = snapshot {
+            let item = decode_row(&r)?;
+            first_seq = item.seq as i64;
+            parsed.push(item);
+        }
+
+        let query = format!(
+            "select * from {} where tag <> '{}' and seq > $1 and seq <= $2 order by seq, id",
+            St::TABLE,
+            SNAPSHOT_TAG
+        );
+        let rows = sqlx::query(&query)
+            .bind(first_seq)
+            .bind(last_seq)
+            .fetch_all(&self.pool)
+            .await?;
+        for r in rows {
+            parsed.push(decode_row(&r)?);
+        }
+        Ok(Some(parsed))
+    }
+}
+
+/// Decode single row of the table of the state
+fn decode_row<St>(r: &SqliteRow) -> Result<Envelope<St>, Error>
+where
+    St: State + VersionedState,
+    St::Update: HasUpdateTag,
+{
+    let body = <SnapshotedUpdate<St>>::deserialize_by_tag::<Json>(
+        &Cow::Owned(r.try_get("tag")?),
+        r.try_get::<i64, &str>("version")? as u16,
+        &serde_json::from_str(r.try_get("body")?)?,
+    )?;
+    let created = DateTime::from_utc(r.try_get::<NaiveDateTime, &str>("created")?, Utc);
+    let meta = Metadata {
+        actor: r.try_get("actor")?,
+        correlation_id: r.try_get("correlation_id")?,
+        causation_id: r.try_get("causation_id")?,
+        headers: serde_json::from_str(r.try_get("headers")?)?,
+    };
+    Ok(
+        Envelope::with_created(r.try_get::<i64, &str>("seq")? as u64, created, body)
+            .with_meta(meta),
+    )
+}
+
+/// Insert single update into the table of the state
+async fn insert<'c, St, E>(executor: E, envelope: Envelope<St>) -> Result<(), Error>
+where
+    St: State + VersionedState,
+    St::Update: HasUpdateTag,
+    E: sqlx::Executor<'c, Database = sqlx::Sqlite>,
+{
+    let update = envelope.update;
+    let tag = format!("{}", update.get_tag());
+    let body = serde_json::to_string(&update.serialize_untagged::<Json>()?)?;
+    let meta = envelope.meta;
+    let headers = serde_json::to_string(&meta.headers)?;
+    let query = format!(
+        "insert into {} (created, seq, version, tag, body, actor, correlation_id, causation_id, headers) \
+         values ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        St::TABLE
+    );
+    let query = sqlx::query(&query)
+        .bind(envelope.created.naive_utc())
+        .bind(envelope.seq as i64)
+        .bind(update.get_version() as i64)
+        .bind(tag)
+        .bind(body)
+        .bind(meta.actor)
+        .bind(meta.correlation_id)
+        .bind(meta.causation_id)
+        .bind(headers)
+        .execute(executor);
+    query.await?;
+    Ok(())
+}
@@ -0,0 +1,349 @@
+pub mod backend;
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{Pool, Sqlite};
+    use append_db::backend::class::{
+        Envelope, HistoryPoint, Metadata, SnapshotedUpdate, State, StateBackend,
+    };
+    use append_db::db::{AppendDb, AppendErr};
+    use append_db::update::{HasUpdateTag, VersionedState};
+    use append_db::*;
+    use chrono::Utc;
+    use futures::TryStreamExt;
+    use serde::{Deserialize, Serialize};
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+    use std::convert::Infallible;
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tokio::time::timeout;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, VersionedState)]
+    struct State0 {
+        field: u64,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, VersionedState)]
+    struct State1 {
+        field: String,
+    }
+
+    #[derive(Clone, Debug, PartialEq, HasUpdateTag)]
+    enum Update1 {
+        Append(String),
+        Set(String),
+    }
+
+    #[derive(Clone, Debug, PartialEq, HasUpdateTag)]
+    enum Update0 {
+        Add(u64),
+        Set(u64),
+    }
+
+    impl State for State0 {
+        type Update = Update0;
+        type Err = Infallible;
+
+        fn update(&mut self, upd: Update0) -> Result<(), Self::Err> {
+            match upd {
+                Update0::Add(v) => self.field += v,
+                Update0::Set(v) => self.field = v,
+            }
+            Ok(())
+        }
+    }
+
+    impl State for State1 {
+        type Update = Update1;
+        type Err = Infallible;
+
+        const TABLE: &'static str = "updates2";
+
+        fn update(&mut self, upd: Self::Update) -> Result<(), Self::Err> {
+            match upd {
+                Update1::Append(s) => self.field.push_str(s.as_str()),
+                Update1::Set(s) => self.field = s,
+            }
+            Ok(())
+        }
+    }
+
+    /// Drop timestamps to compare stored updates
+    fn stripped<St: State>(upds: Vec<Envelope<St>>) -> Vec<(u64, SnapshotedUpdate<St>)> {
+        upds.into_iter().map(|e| (e.seq, e.update)).collect()
+    }
+
+    /// Fresh migrated database in a temporary directory. The directory is removed
+    /// when the returned guard is dropped.
+    async fn temp_pool() -> (TempDir, Pool) {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let options = SqliteConnectOptions::new()
+            .filename(dir.path().join("db.sqlite"))
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .connect_with(options)
+            .await
+            .expect("connect");
+        sqlx::migrate!("./migrations")
+            .run(&pool)
+            .await
+            .expect("migrate");
+        (dir, pool)
+    }
+
+    #[tokio::test]
+    async fn sqlite_updates() {
+        let (_dir, pool) = temp_pool().await;
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Sqlite::new(pool), state0.clone());
+        db.update(Update0::Add(1)).await.expect("update");
+        assert_eq!(db.get().field, 43);
+        db.update(Update0::Set(4)).await.expect("update");
+        assert_eq!(db.get().field, 4);
+
+        let db = AppendDb::new(db.backend, state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 4);
+        assert_eq!(db.seq(), 2);
+    }
+
+    #[tokio::test]
+    async fn sqlite_reopen() {
+        let (dir, pool) = temp_pool().await;
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Sqlite::new(pool.clone()), state0.clone());
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+        drop(db);
+        pool.close().await;
+
+        let options = SqliteConnectOptions::new().filename(dir.path().join("db.sqlite"));
+        let pool = SqlitePoolOptions::new()
+            .connect_with(options)
+            .await
+            .expect("connect");
+        let db = AppendDb::new(Sqlite::new(pool), state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 44);
+        assert_eq!(db.seq(), 2);
+    }
+
+    #[tokio::test]
+    async fn two_tables_sqlite_reconstruct_snapshot() {
+        let (_dir, pool) = temp_pool().await;
+        let sqlite = Sqlite::new(pool);
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(sqlite.clone(), state0.clone());
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Set(4)).await.expect("update");
+
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 4);
+
+        let state1 = State1 {
+            field: String::new(),
+        };
+        let db1 = AppendDb::new(sqlite.duplicate(), state1.clone());
+        db1.update(Update1::Append("Hello ') drop table updates2;".to_string()))
+            .await
+            .expect("update");
+        db1.snapshot().await.expect("snapshot");
+        db1.update(Update1::Set(
+            "Hello world! ') drop table updates2;".to_string(),
+        ))
+        .await
+        .expect("update");
+
+        db1.load().await.expect("load");
+        assert_eq!(
+            db1.get().field,
+            "Hello world! ') drop table updates2;".to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn sqlite_update_many() {
+        let (_dir, pool) = temp_pool().await;
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Sqlite::new(pool), state0.clone());
+        db.update_many(vec![Update0::Set(4), Update0::Add(1)])
+            .await
+            .expect("update");
+        assert_eq!(db.get().field, 5);
+
+        let upds = db.backend.updates().await.expect("collected");
+        assert_eq!(
+            stripped(upds),
+            vec![
+                (1, SnapshotedUpdate::Incremental(Update0::Set(4))),
+                (2, SnapshotedUpdate::Incremental(Update0::Add(1))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sqlite_update_meta() {
+        let (_dir, pool) = temp_pool().await;
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Sqlite::new(pool), state0);
+        let meta = Metadata::default()
+            .with_actor("operator")
+            .with_correlation_id("request-1")
+            .with_causation_id("command-1")
+            .with_header("ip", "127.0.0.1");
+        db.update_meta(Update0::Add(1), meta.clone())
+            .await
+            .expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let upds = db.backend.updates().await.expect("collected");
+        assert_eq!(upds[0].meta, meta);
+        assert_eq!(upds[1].meta, Metadata::default());
+    }
+
+    #[tokio::test]
+    async fn sqlite_update_if() {
+        let (_dir, pool) = temp_pool().await;
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Sqlite::new(pool), state0.clone());
+        db.update_if(0, Update0::Add(1)).await.expect("update");
+        let res = db.update_if(0, Update0::Set(4)).await;
+        assert!(matches!(res, Err(AppendErr::VersionMismatch { .. })));
+
+        let db = AppendDb::new(db.backend, state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get_versioned(), (State0 { field: 43 }, 1));
+    }
+
+    #[tokio::test]
+    async fn sqlite_out_of_order_writes() {
+        let (_dir, pool) = temp_pool().await;
+        let sqlite = Sqlite::new(pool);
+        sqlite
+            .write(Envelope::new(
+                2,
+                SnapshotedUpdate::Incremental(Update0::Add(1)),
+            ))
+            .await
+            .expect("write");
+        sqlite
+            .write(Envelope::new(
+                1,
+                SnapshotedUpdate::Snapshot(State0 { field: 4 }),
+            ))
+            .await
+            .expect("write");
+        sqlite
+            .write(Envelope::new(
+                1,
+                SnapshotedUpdate::Incremental(Update0::Set(4)),
+            ))
+            .await
+            .expect("write");
+
+        let db = AppendDb::new(sqlite, State0 { field: 42 });
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 5);
+        assert_eq!(db.seq(), 2);
+    }
+
+    #[tokio::test]
+    async fn sqlite_write_while_streaming() {
+        let (_dir, pool) = temp_pool().await;
+        let db = AppendDb::new(Sqlite::new(pool), State0 { field: 42 });
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let mut updates = db.backend.updates_stream();
+        updates.try_next().await.expect("streamed");
+        let write = db.update(Update0::Add(1));
+        timeout(Duration::from_secs(5), write)
+            .await
+            .expect("not blocked")
+            .expect("update");
+    }
+
+    async fn fill_for_compaction(db: &AppendDb<Sqlite<State0>>) {
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+    }
+
+    #[tokio::test]
+    async fn sqlite_compact() {
+        let (_dir, pool) = temp_pool().await;
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Sqlite::new(pool), state0.clone());
+        fill_for_compaction(&db).await;
+
+        let keep = |n| NonZeroUsize::new(n).expect("non zero");
+        assert_eq!(db.compact(keep(3)).await.expect("compact"), 0);
+        assert_eq!(db.compact(keep(2)).await.expect("compact"), 1);
+        assert_eq!(db.compact(keep(1)).await.expect("compact"), 2);
+        let res = db.state_at(HistoryPoint::Seq(1)).await;
+        assert!(matches!(res, Err(AppendErr::Compacted)));
+        assert_eq!(
+            db.state_at(HistoryPoint::Seq(2))
+                .await
+                .expect("state")
+                .field,
+            44
+        );
+
+        let db = AppendDb::new(db.backend, state0);
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 45);
+        assert_eq!(db.seq(), 3);
+    }
+
+    #[tokio::test]
+    async fn sqlite_compact_archive() {
+        let (_dir, pool) = temp_pool().await;
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Sqlite::new(pool.clone()).with_archive(), state0.clone());
+        fill_for_compaction(&db).await;
+
+        let keep = NonZeroUsize::new(1).expect("non zero");
+        assert_eq!(db.compact(keep).await.expect("compact"), 3);
+        let (archived,): (i64,) = sqlx::query_as("select count(*) from updates_archive")
+            .fetch_one(&pool)
+            .await
+            .expect("count");
+        assert_eq!(archived, 3);
+
+        db.load().await.expect("load");
+        assert_eq!(db.get().field, 45);
+    }
+
+    #[tokio::test]
+    async fn sqlite_state_at() {
+        let (_dir, pool) = temp_pool().await;
+        let state0 = State0 { field: 42 };
+        let db = AppendDb::new(Sqlite::new(pool), state0.clone());
+        let before = Utc::now();
+        db.update(Update0::Add(1)).await.expect("update");
+        db.update(Update0::Add(1)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let middle = Utc::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        db.update(Update0::Set(4)).await.expect("update");
+        db.snapshot().await.expect("snapshot");
+        db.update(Update0::Add(1)).await.expect("update");
+
+        let at = |seq| db.state_at(HistoryPoint::Seq(seq));
+        assert_eq!(at(0).await.expect("state"), state0);
+        assert_eq!(at(2).await.expect("state").field, 44);
+        assert_eq!(at(3).await.expect("state").field, 4);
+
+        let at = |t| db.state_at(HistoryPoint::Time(t));
+        assert_eq!(at(before).await.expect("state"), state0);
+        assert_eq!(at(middle).await.expect("state").field, 44);
+        assert_eq!(at(Utc::now()).await.expect("state").field, 5);
+    }
+}